@@ -370,6 +370,7 @@ async fn main() -> () {
 			.at(parent)
 			.build_async()
 			.await
+			.expect("failed to build the remote externalities")
 			.execute_with(|| {
 				if spec >= SPEC_REFCOUNT_U32 {
 					migrate_back_to_u8_ref_count()
@@ -387,6 +388,7 @@ async fn main() -> () {
 			.at(parent)
 			.build_async()
 			.await
+			.expect("failed to build the remote externalities")
 			.execute_with(|| {
 				if spec >= SPEC_REFCOUNT_U32 {
 					migrate_back_to_u8_ref_count()
@@ -450,6 +452,7 @@ async fn main() -> () {
 		.module("PhragmenElection")
 		.build_async()
 		.await
+		.expect("failed to build the remote externalities")
 		.execute_with(|| (Elections::members_ids(), Elections::runners_up_ids()));
 
 	let stat_of = |v: &AccountId| -> Stat {
@@ -487,6 +490,7 @@ async fn main() -> () {
 		.module("System")
 		.build_async()
 		.await
+		.expect("failed to build the remote externalities")
 		.execute_with(|| {
 			migrate_back_to_u8_ref_count();
 			let mut corrupt = 0;
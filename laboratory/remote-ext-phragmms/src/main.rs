@@ -279,6 +279,7 @@ async fn run(now: Hash, era: u32, wrt: &mut csv::Writer<std::fs::File>) {
 		.at(now)
 		.build_async()
 		.await
+		.expect("failed to build the remote externalities")
 		.execute_with(|| {
 			println!("⏰ Scraping done at block {:?}.", now);
 			let mut all_nominators: Vec<(AccountId, VoteWeight, Vec<AccountId>)> = Vec::new();
@@ -44,7 +44,8 @@ async fn main() -> () {
 			"Kusama,0x7f13b9c87b6ba0845ea69a4cde233f2e8979666e86fe62eeba4982da8133023c,.bin".into(),
 		))
 		.build()
-		.await;
+		.await
+		.expect("failed to build the remote externalities");
 
 	Executive::migrate(
 		state,
@@ -0,0 +1,90 @@
+//! The rpc client, over either http or websocket.
+
+use crate::Error;
+use jsonrpsee_http_client::{HttpClient, HttpConfig};
+use jsonrpsee_types::jsonrpc::{from_value, Params};
+use jsonrpsee_ws_client::{WsClient, WsConfig};
+use serde::de::DeserializeOwned;
+
+/// The maximum size of a request or response. Some storage values, such as the runtime code, are
+/// well beyond the default limits.
+pub const MAX_BODY_SIZE: u32 = u32::max_value();
+
+/// The transport of an [`RpcClient`].
+pub enum Transport {
+	/// For `http://` and `https://` uris.
+	Http(HttpClient),
+	/// For `ws://` and `wss://` uris.
+	Ws(WsClient),
+}
+
+/// An rpc client, whose transport is chosen based on the scheme of the uri.
+pub struct RpcClient {
+	uri: String,
+	transport: Transport,
+}
+
+impl RpcClient {
+	/// Connect to the node at `uri`.
+	///
+	/// Websocket is used if the scheme of `uri` is `ws` or `wss`, else http.
+	pub async fn new(uri: &str) -> Result<Self, Error> {
+		let connection_error = |e: &dyn std::fmt::Display| Error::Connection {
+			uri: uri.to_string(),
+			reason: e.to_string(),
+		};
+		let transport = if Self::is_ws(uri) {
+			let config = WsConfig { max_request_body_size: MAX_BODY_SIZE, ..Default::default() };
+			WsClient::new(uri, config).await.map(Transport::Ws).map_err(|e| connection_error(&e))?
+		} else {
+			HttpClient::new(uri, HttpConfig { max_request_body_size: MAX_BODY_SIZE })
+				.map(Transport::Http)
+				.map_err(|e| connection_error(&e))?
+		};
+		Ok(Self { uri: uri.to_string(), transport })
+	}
+
+	/// The uri of the node.
+	pub fn uri(&self) -> &str {
+		&self.uri
+	}
+
+	/// The transport, e.g. to subscribe via websocket.
+	pub fn transport(&self) -> &Transport {
+		&self.transport
+	}
+
+	/// True if `uri` should be connected to via websocket.
+	pub fn is_ws(uri: &str) -> bool {
+		uri.starts_with("ws://") || uri.starts_with("wss://")
+	}
+
+	/// Send a request to `method`, and decode its response.
+	pub async fn request<T: DeserializeOwned>(
+		&self,
+		method: &'static str,
+		params: Params,
+	) -> Result<T, Error> {
+		let rpc_error = |e| Error::rpc(&self.uri, method, e);
+		match &self.transport {
+			Transport::Http(client) => {
+				let json_value = client.request(method, params).await.map_err(rpc_error)?;
+				from_value(json_value).map_err(|e| Error::decode(method, e))
+			}
+			Transport::Ws(client) => client.request(method, params).await.map_err(rpc_error),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn transport_is_chosen_by_scheme() {
+		assert!(RpcClient::is_ws("ws://localhost:9944"));
+		assert!(RpcClient::is_ws("wss://kusama-rpc.polkadot.io"));
+		assert!(!RpcClient::is_ws("http://localhost:9933"));
+		assert!(!RpcClient::is_ws("https://kusama-rpc.polkadot.io"));
+	}
+}
@@ -0,0 +1,456 @@
+//! Inspecting, and persisting, the changes made to a state.
+//!
+//! After some code has been executed in [`TestExternalities`], [`StateDiff`] shows which keys
+//! were added, removed or modified compared to the original state, grouped by storage item if
+//! metadata is given. The modified state can then be saved as a new snapshot via [`save_state`],
+//! so that a multi-step investigation can continue from it.
+//!
+//! Any two states can be compared the same way, e.g. the states before and after a migration, or a
+//! local simulation and the real next block, see [`StateDiff::of_snapshots`] and
+//! [`StateDiff::of_externalities`]. [`StateDiff::summary`] counts the changes of each module, and
+//! [`StateDiff::report`] prints them with a few examples each, decoding the values where their
+//! type is simple enough.
+
+use crate::{
+	export::{self, UNKNOWN},
+	KeyPair, Snapshot, SnapshotError, SnapshotHeader, SnapshotReader, TestExternalities,
+};
+use codec::{Compact, Decode};
+use sp_core::{crypto::AccountId32, hexdisplay::HexDisplay, storage::StorageKey, H256};
+use std::{collections::BTreeMap, fmt, path::Path};
+use sub_storage::metadata::{Metadata, StorageLayout};
+
+/// The filter that is added to the header of snapshots saved by [`save_state`].
+pub const MODIFIED: &str = "modified";
+
+/// The change of a single key.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Change {
+	/// The key did not exist, and now has the given value.
+	Added(Vec<u8>),
+	/// The key had the given value, and now does not exist.
+	Removed(Vec<u8>),
+	/// The value of the key changed.
+	Modified { before: Vec<u8>, after: Vec<u8> },
+}
+
+/// All the changes of a state, grouped by `(module, item)`.
+///
+/// Keys that cannot be identified are grouped under `(UNKNOWN, "")`.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct StateDiff {
+	/// The changes of each storage item.
+	pub items: BTreeMap<(String, String), Vec<(StorageKey, Change)>>,
+}
+
+impl StateDiff {
+	/// Compute the diff between the `before` and `after` states.
+	pub fn compute(before: Vec<KeyPair>, after: Vec<KeyPair>, metadata: Option<&Metadata>) -> Self {
+		let mut after =
+			after.into_iter().map(|(k, v)| (k, v.0)).collect::<BTreeMap<StorageKey, Vec<u8>>>();
+		let mut diff = Self::default();
+
+		for (key, before) in before {
+			match after.remove(&key) {
+				None => diff.push(key, Change::Removed(before.0), metadata),
+				Some(after) if after != before.0 => {
+					diff.push(key, Change::Modified { before: before.0, after }, metadata)
+				}
+				Some(_) => (),
+			}
+		}
+		for (key, value) in after {
+			diff.push(key, Change::Added(value), metadata);
+		}
+		diff
+	}
+
+	/// Compute the diff between the snapshot at `path`, and the current state of `ext`.
+	pub fn of_snapshot(
+		path: &Path,
+		ext: &TestExternalities,
+		metadata: Option<&Metadata>,
+	) -> Result<Self, SnapshotError> {
+		let before = SnapshotReader::open(path)?.collect::<Result<Vec<_>, _>>()?;
+		Ok(Self::compute(before, export::pairs_of(ext), metadata))
+	}
+
+	/// Compute the diff between the snapshots at `before` and `after`.
+	pub fn of_snapshots(
+		before: &Path,
+		after: &Path,
+		metadata: Option<&Metadata>,
+	) -> Result<Self, SnapshotError> {
+		let read = |path: &Path| -> Result<Vec<KeyPair>, SnapshotError> {
+			SnapshotReader::open(path)?.collect()
+		};
+		Ok(Self::compute(read(before)?, read(after)?, metadata))
+	}
+
+	/// Compute the diff between the states of `before` and `after`, including their uncommitted
+	/// changes.
+	pub fn of_externalities(
+		before: &TestExternalities,
+		after: &TestExternalities,
+		metadata: Option<&Metadata>,
+	) -> Self {
+		Self::compute(export::pairs_of(before), export::pairs_of(after), metadata)
+	}
+
+	fn push(&mut self, key: StorageKey, change: Change, metadata: Option<&Metadata>) {
+		let item = metadata
+			.and_then(|m| m.storage_of_key(&key.0))
+			.map(|info| (info.module_prefix.clone(), info.name.clone()))
+			.unwrap_or_else(|| (UNKNOWN.to_string(), String::new()));
+		self.items.entry(item).or_default().push((key, change));
+	}
+
+	/// True if nothing changed.
+	pub fn is_empty(&self) -> bool {
+		self.items.is_empty()
+	}
+
+	/// Iterate over all the changes, regardless of their storage item.
+	pub fn changes(&self) -> impl Iterator<Item = &(StorageKey, Change)> {
+		self.items.values().flatten()
+	}
+
+	/// The number of `(added, removed, modified)` keys.
+	pub fn counts(&self) -> (usize, usize, usize) {
+		Self::counts_of(self.changes())
+	}
+
+	/// The changes of each module, keyed by module name.
+	pub fn summary(&self) -> BTreeMap<String, ModuleSummary> {
+		let mut summary = BTreeMap::<String, ModuleSummary>::new();
+		for ((module, _), changes) in self.items.iter() {
+			let entry = summary.entry(module.clone()).or_default();
+			changes.iter().for_each(|(_, change)| entry.push(change));
+		}
+		summary
+	}
+
+	/// A printable report of the changes, with at most `max_examples` changes per storage item.
+	///
+	/// If `metadata` is given, values whose type is simple enough are decoded, see
+	/// [`decode_value`].
+	pub fn report<'a>(&'a self, metadata: Option<&'a Metadata>, max_examples: usize) -> Report<'a> {
+		Report { diff: self, metadata, max_examples }
+	}
+
+	fn counts_of<'a>(
+		changes: impl Iterator<Item = &'a (StorageKey, Change)>,
+	) -> (usize, usize, usize) {
+		changes.fold((0, 0, 0), |(a, r, m), (_, change)| match change {
+			Change::Added(_) => (a + 1, r, m),
+			Change::Removed(_) => (a, r + 1, m),
+			Change::Modified { .. } => (a, r, m + 1),
+		})
+	}
+}
+
+impl fmt::Display for StateDiff {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		for ((module, item), changes) in self.items.iter() {
+			let (added, removed, modified) = Self::counts_of(changes.iter());
+			writeln!(f, "{}::{}: +{} -{} ~{}", module, item, added, removed, modified)?;
+			for (key, change) in changes {
+				let key = HexDisplay::from(&key.0);
+				match change {
+					Change::Added(v) => writeln!(f, "  + 0x{} => 0x{}", key, HexDisplay::from(v))?,
+					Change::Removed(v) => {
+						writeln!(f, "  - 0x{} => 0x{}", key, HexDisplay::from(v))?
+					}
+					Change::Modified { before, after } => writeln!(
+						f,
+						"  ~ 0x{} => 0x{} -> 0x{}",
+						key,
+						HexDisplay::from(before),
+						HexDisplay::from(after)
+					)?,
+				}
+			}
+		}
+		Ok(())
+	}
+}
+
+/// The number of keys, and the total size of their values, of one kind of change.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct ChangeStats {
+	/// The number of keys.
+	pub keys: usize,
+	/// The total size of the values, in bytes. For modified keys, this is the size after.
+	pub bytes: usize,
+}
+
+impl ChangeStats {
+	fn push(&mut self, bytes: usize) {
+		self.keys += 1;
+		self.bytes += bytes;
+	}
+}
+
+/// The changes of a single module.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct ModuleSummary {
+	/// The added keys.
+	pub added: ChangeStats,
+	/// The removed keys.
+	pub removed: ChangeStats,
+	/// The modified keys.
+	pub modified: ChangeStats,
+	/// The net change of the size of the module's state, in bytes.
+	pub delta: i64,
+}
+
+impl ModuleSummary {
+	fn push(&mut self, change: &Change) {
+		match change {
+			Change::Added(v) => {
+				self.added.push(v.len());
+				self.delta += v.len() as i64;
+			}
+			Change::Removed(v) => {
+				self.removed.push(v.len());
+				self.delta -= v.len() as i64;
+			}
+			Change::Modified { before, after } => {
+				self.modified.push(after.len());
+				self.delta += after.len() as i64 - before.len() as i64;
+			}
+		}
+	}
+}
+
+impl fmt::Display for ModuleSummary {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(
+			f,
+			"+{} ({} bytes) -{} ({} bytes) ~{} ({} bytes), net {:+} bytes",
+			self.added.keys,
+			self.added.bytes,
+			self.removed.keys,
+			self.removed.bytes,
+			self.modified.keys,
+			self.modified.bytes,
+			self.delta,
+		)
+	}
+}
+
+/// A printable report of a [`StateDiff`], see [`StateDiff::report`].
+pub struct Report<'a> {
+	diff: &'a StateDiff,
+	metadata: Option<&'a Metadata>,
+	max_examples: usize,
+}
+
+impl<'a> Report<'a> {
+	fn value(&self, module: &str, item: &str, raw: &[u8]) -> String {
+		self.metadata
+			.and_then(|m| m.storage(module, item))
+			.and_then(|info| match &info.layout {
+				StorageLayout::Plain { value } => decode_value(value, raw),
+				StorageLayout::Map { value, .. } => decode_value(value, raw),
+				StorageLayout::DoubleMap { value, .. } => decode_value(value, raw),
+			})
+			.unwrap_or_else(|| format!("0x{}", HexDisplay::from(&raw)))
+	}
+}
+
+impl<'a> fmt::Display for Report<'a> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		for (module, summary) in self.diff.summary() {
+			writeln!(f, "{}: {}", module, summary)?;
+			for ((_, item), changes) in self.diff.items.iter().filter(|((m, _), _)| *m == module) {
+				let (added, removed, modified) = StateDiff::counts_of(changes.iter());
+				writeln!(f, "  {}::{}: +{} -{} ~{}", module, item, added, removed, modified)?;
+				for (key, change) in changes.iter().take(self.max_examples) {
+					let key = HexDisplay::from(&key.0);
+					match change {
+						Change::Added(v) => {
+							writeln!(f, "    + 0x{} => {}", key, self.value(&module, item, v))?
+						}
+						Change::Removed(v) => {
+							writeln!(f, "    - 0x{} => {}", key, self.value(&module, item, v))?
+						}
+						Change::Modified { before, after } => writeln!(
+							f,
+							"    ~ 0x{} => {} -> {}",
+							key,
+							self.value(&module, item, before),
+							self.value(&module, item, after),
+						)?,
+					}
+				}
+				if changes.len() > self.max_examples {
+					writeln!(f, "    .. and {} more", changes.len() - self.max_examples)?;
+				}
+			}
+		}
+		Ok(())
+	}
+}
+
+/// Decode the given raw value of the type named `ty` in the metadata, e.g. `T::BlockNumber`.
+///
+/// Only primitives, a few common aliases of them, and vectors thereof are supported, since the
+/// metadata only knows types by name. Returns `None` if the type is not supported, or if `raw`
+/// does not decode exactly.
+pub fn decode_value(ty: &str, raw: &[u8]) -> Option<String> {
+	let input = &mut &*raw;
+	let decoded = decode_type(ty.trim(), input)?;
+	if input.is_empty() {
+		Some(decoded)
+	} else {
+		None
+	}
+}
+
+fn decode_type(ty: &str, input: &mut &[u8]) -> Option<String> {
+	fn decode<T: Decode + fmt::Debug>(input: &mut &[u8]) -> Option<String> {
+		T::decode(input).ok().map(|t| format!("{:?}", t))
+	}
+
+	if let Some(inner) = ty.strip_prefix("Vec<").and_then(|t| t.strip_suffix('>')) {
+		let len = <Compact<u32>>::decode(input).ok()?.0;
+		let items =
+			(0..len).map(|_| decode_type(inner.trim(), input)).collect::<Option<Vec<_>>>()?;
+		return Some(format!("[{}]", items.join(", ")));
+	}
+
+	match ty {
+		"bool" => decode::<bool>(input),
+		"u8" => decode::<u8>(input),
+		"u16" => decode::<u16>(input),
+		"u32" | "T::BlockNumber" | "BlockNumber" | "T::Index" | "EraIndex" | "SessionIndex" => {
+			decode::<u32>(input)
+		}
+		"u64" | "T::Moment" | "Moment" => decode::<u64>(input),
+		"u128" | "T::Balance" | "BalanceOf<T>" | "BalanceOf<T, I>" => decode::<u128>(input),
+		"T::Hash" | "Hash" | "H256" => decode::<H256>(input),
+		"T::AccountId" | "AccountId" => AccountId32::decode(input).ok().map(|a| a.to_string()),
+		_ => None,
+	}
+}
+
+/// Save the current state of `ext`, including its uncommitted changes, as a snapshot at `path`.
+///
+/// `original` is the header of the snapshot that `ext` was built from. The new snapshot keeps its
+/// origin, but is marked with the [`MODIFIED`] filter, so that its state root is never verified.
+pub fn save_state(
+	ext: &TestExternalities,
+	original: &SnapshotHeader,
+	path: &Path,
+) -> Result<(), SnapshotError> {
+	let mut filter = original.filter.clone();
+	if !filter.iter().any(|f| f == MODIFIED) {
+		filter.push(MODIFIED.into());
+	}
+	let header = SnapshotHeader { filter, created_at: SnapshotHeader::now(), ..original.clone() };
+	Snapshot::write(path, &header, &export::pairs_of(ext))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{snapshot::tests::test_header, Builder, CacheMode};
+	use sp_core::storage::StorageData;
+
+	#[test]
+	fn compare_works() {
+		let dir = tempfile::tempdir().unwrap();
+		let before = dir.path().join("before.bin");
+		let after = dir.path().join("after.bin");
+		let pair = |k: &[u8], v: &[u8]| (StorageKey(k.to_vec()), StorageData(v.to_vec()));
+		Snapshot::write(
+			&before,
+			&test_header(),
+			&[pair(b"a", b"1"), pair(b"b", b"22"), pair(b"c", b"3")],
+		)
+		.unwrap();
+		Snapshot::write(
+			&after,
+			&test_header(),
+			&[pair(b"a", b"111"), pair(b"c", b"3"), pair(b"d", b"4")],
+		)
+		.unwrap();
+
+		let diff = StateDiff::of_snapshots(&before, &after, None).unwrap();
+		let summary = diff.summary();
+		let unknown = summary[export::UNKNOWN];
+		assert_eq!(unknown.added, ChangeStats { keys: 1, bytes: 1 });
+		assert_eq!(unknown.removed, ChangeStats { keys: 1, bytes: 2 });
+		assert_eq!(unknown.modified, ChangeStats { keys: 1, bytes: 3 });
+		assert_eq!(unknown.delta, 1);
+
+		let report = diff.report(None, 1).to_string();
+		assert!(report.contains("net +1 bytes"));
+		assert!(report.contains("~ 0x61 => 0x31 -> 0x313131"));
+		assert!(report.contains(".. and 2 more"));
+	}
+
+	#[test]
+	fn decode_value_works() {
+		use codec::Encode;
+		assert_eq!(decode_value("T::BlockNumber", &5u32.encode()), Some("5".into()));
+		assert_eq!(decode_value("Vec<u32>", &vec![1u32, 2].encode()), Some("[1, 2]".into()));
+		assert_eq!(decode_value("BalanceOf<T>", &7u128.encode()), Some("7".into()));
+		// trailing bytes.
+		assert_eq!(decode_value("u8", &[1, 2]), None);
+		// unknown type.
+		assert_eq!(decode_value("Exposure<T::AccountId, BalanceOf<T>>", &[]), None);
+	}
+
+	#[tokio::test]
+	async fn diff_and_save_state_works() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("diff.bin");
+		let modified_path = dir.path().join("diff-modified.bin");
+		let pairs = vec![
+			(StorageKey(b"changed".to_vec()), StorageData(b"before".to_vec())),
+			(StorageKey(b"removed".to_vec()), StorageData(b"value".to_vec())),
+			(StorageKey(b"same".to_vec()), StorageData(b"value".to_vec())),
+		];
+		Snapshot::write(&path, &test_header(), &pairs).unwrap();
+
+		let mut ext = Builder::new()
+			.cache_mode(CacheMode::Offline { path: path.clone() })
+			.build()
+			.await
+			.unwrap();
+		ext.execute_with(|| {
+			sp_io::storage::set(b"changed", b"after");
+			sp_io::storage::clear(b"removed");
+			sp_io::storage::set(b"added", b"new");
+		});
+
+		let diff = StateDiff::of_snapshot(&path, &ext, None).unwrap();
+		assert_eq!(diff.counts(), (1, 1, 1));
+		assert_eq!(
+			diff.items[&(export::UNKNOWN.to_string(), String::new())],
+			vec![
+				(
+					StorageKey(b"changed".to_vec()),
+					Change::Modified { before: b"before".to_vec(), after: b"after".to_vec() }
+				),
+				(StorageKey(b"removed".to_vec()), Change::Removed(b"value".to_vec())),
+				(StorageKey(b"added".to_vec()), Change::Added(b"new".to_vec())),
+			]
+		);
+
+		// chain the modified state into a new externalities.
+		save_state(&ext, &test_header(), &modified_path).unwrap();
+		assert!(Snapshot::load_header(&modified_path).unwrap().filter.contains(&"modified".into()));
+		Builder::new()
+			.cache_mode(CacheMode::Offline { path: modified_path.clone() })
+			.build()
+			.await
+			.unwrap()
+			.execute_with(|| {
+				assert_eq!(sp_io::storage::get(b"changed"), Some(b"after".to_vec()));
+				assert_eq!(sp_io::storage::get(b"removed"), None);
+			});
+	}
+}
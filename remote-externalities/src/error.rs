@@ -0,0 +1,123 @@
+//! The errors of building remote externalities.
+
+use crate::{Hash, SnapshotError};
+use jsonrpsee_types::error::Error as RpcError;
+use std::{fmt, path::PathBuf};
+
+/// The json-rpc error code with which substrate nodes refuse to serve unsafe methods.
+const METHOD_NOT_FOUND: i64 = -32601;
+
+/// Errors that can happen while building remote externalities.
+#[derive(Debug)]
+pub enum Error {
+	/// Could not connect to the node at `uri`.
+	Connection { uri: String, reason: String },
+	/// An rpc call to `method` failed, or returned something that could not be decoded.
+	Rpc { method: &'static str, reason: String },
+	/// The node refused to serve `method`, most likely since it is unsafe. Such nodes must be
+	/// started with `--rpc-methods=Unsafe`.
+	UnsafeRpcNotAllowed { method: &'static str },
+	/// The requested block does not exist on the node.
+	BlockNotFound(Hash),
+	/// The cache at `path` could not be read or written.
+	Cache { path: PathBuf, error: SnapshotError },
+	/// The state root of the built externalities does not match the one of the block.
+	StateRootMismatch { expected: Hash, computed: Hash },
+	/// An error happened while scraping the state at block `at`.
+	Scrape { at: Hash, error: Box<Error> },
+	/// The state has no runtime code, i.e. the `:code` key was not scraped.
+	NoRuntimeCode,
+	/// Calling `method` of the Wasm runtime failed.
+	Runtime { method: String, reason: String },
+	/// The overrides file at `path` could not be read.
+	Overrides { path: PathBuf, reason: String },
+}
+
+impl Error {
+	/// An error returned by the rpc client of the node at `uri` while calling `method`.
+	///
+	/// Errors are classified by their json-rpc error code, or their kind, never by their message,
+	/// whose wording depends on the node.
+	pub(crate) fn rpc(uri: &str, method: &'static str, error: RpcError) -> Self {
+		let reason = error.to_string();
+		match &error {
+			RpcError::Request(e) if e.code.code() == METHOD_NOT_FOUND => {
+				Self::UnsafeRpcNotAllowed { method }
+			}
+			RpcError::TransportError(_) => Self::Connection { uri: uri.to_string(), reason },
+			_ => Self::Rpc { method, reason },
+		}
+	}
+
+	/// The response of `method` could not be decoded.
+	pub(crate) fn decode<E: fmt::Display>(method: &'static str, error: E) -> Self {
+		Self::Rpc { method, reason: format!("failed to decode the response: {}", error) }
+	}
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::Connection { uri, reason } => {
+				write!(f, "failed to connect to node at {}: {}", uri, reason)
+			}
+			Self::Rpc { method, reason } => write!(f, "rpc call {} failed: {}", method, reason),
+			Self::UnsafeRpcNotAllowed { method } => write!(
+				f,
+				"rpc call {} is unsafe, and not allowed by the node. Start the node with \
+				 --rpc-methods=Unsafe",
+				method
+			),
+			Self::BlockNotFound(hash) => write!(f, "block {:?} does not exist", hash),
+			Self::Cache { path, error } => write!(f, "cache {:?}: {}", path, error),
+			Self::StateRootMismatch { expected, computed } => write!(
+				f,
+				"state root mismatch: expected {:?}, computed {:?}. The scraped state is \
+				 incomplete or corrupted.",
+				expected, computed
+			),
+			Self::Scrape { at, error } => write!(f, "while scraping block {:?}: {}", at, error),
+			Self::NoRuntimeCode => write!(f, "the state has no runtime code at `:code`"),
+			Self::Runtime { method, reason } => {
+				write!(f, "runtime call {} failed: {}", method, reason)
+			}
+			Self::Overrides { path, reason } => write!(f, "overrides {:?}: {}", path, reason),
+		}
+	}
+}
+
+impl std::error::Error for Error {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		match self {
+			Self::Cache { error, .. } => Some(error),
+			Self::Scrape { error, .. } => Some(error.as_ref()),
+			_ => None,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn rpc_errors_are_classified_by_code() {
+		use jsonrpsee_types::{
+			error::Error as RpcError,
+			jsonrpc::{self, ErrorCode},
+		};
+		let request_error = |code, message: &str| {
+			RpcError::Request(jsonrpc::Error { code, message: message.into(), data: None })
+		};
+
+		let uri = "ws://localhost:9944";
+		let unsafe_call = request_error(ErrorCode::MethodNotFound, "any wording");
+		assert!(matches!(
+			Error::rpc(uri, "state_getPairs", unsafe_call),
+			Error::UnsafeRpcNotAllowed { method: "state_getPairs" }
+		));
+		// the message is not looked at.
+		let other = request_error(ErrorCode::InvalidParams, "unsafe, failed to connect");
+		assert!(matches!(Error::rpc(uri, "state_getKeysPaged", other), Error::Rpc { .. }));
+	}
+}
@@ -0,0 +1,117 @@
+//! Executing the real Wasm runtime of a chain against a scraped state.
+//!
+//! This avoids compiling a runtime such as `kusama-runtime` natively: the Wasm blob is either read
+//! from the `:code` key of the state, or from a local file, and runtime apis are called by name,
+//! with scale encoded arguments. Only available with the `executor` feature.
+//!
+//! ```ignore
+//! let mut ext = Builder::new().cache_mode(CacheMode::Offline { path }).build().await?;
+//! let runtime = RuntimeExecutor::from_state(&mut ext)?;
+//! println!("{:?}", runtime.version(&mut ext)?);
+//! let weight = runtime.call(&mut ext, "TryRuntime_on_runtime_upgrade", &[])?;
+//! ```
+
+use crate::{Error, TestExternalities, LOG_TARGET};
+use codec::Decode;
+use log::*;
+use sc_executor::{WasmExecutionMethod, WasmExecutor};
+use sc_executor_common::wasm_runtime::MissingHostFunctions;
+use sp_core::{hashing::blake2_256, storage::well_known_keys};
+use sp_version::RuntimeVersion;
+use sp_wasm_interface::HostFunctions as _;
+use std::{fs, path::Path};
+
+/// The maximum number of runtime instances kept by the executor.
+const MAX_RUNTIME_INSTANCES: usize = 8;
+
+/// A Wasm runtime, ready to be called.
+pub struct RuntimeExecutor {
+	code: Vec<u8>,
+	code_hash: Vec<u8>,
+	executor: WasmExecutor,
+}
+
+impl RuntimeExecutor {
+	/// Use the given Wasm `code`, with the given number of heap pages, or the executor's default.
+	pub fn new(code: Vec<u8>, heap_pages: Option<u64>) -> Self {
+		let executor = WasmExecutor::new(
+			WasmExecutionMethod::Interpreted,
+			heap_pages,
+			sp_io::SubstrateHostFunctions::host_functions(),
+			MAX_RUNTIME_INSTANCES,
+			None,
+		);
+		let code_hash = blake2_256(&code).to_vec();
+		Self { code, code_hash, executor }
+	}
+
+	/// Use the runtime stored in the `:code` key of `ext`, with the `:heappages` of `ext`.
+	pub fn from_state(ext: &mut TestExternalities) -> Result<Self, Error> {
+		let (code, heap_pages) = ext.execute_with(|| {
+			let code = sp_io::storage::get(well_known_keys::CODE);
+			let heap_pages = sp_io::storage::get(well_known_keys::HEAP_PAGES)
+				.and_then(|raw| u64::decode(&mut &*raw).ok());
+			(code, heap_pages)
+		});
+		let code = code.ok_or(Error::NoRuntimeCode)?;
+		info!(target: LOG_TARGET, "using runtime of {} bytes from the state", code.len());
+		Ok(Self::new(code, heap_pages))
+	}
+
+	/// Use the Wasm runtime at `path`, e.g. a `*.compact.wasm` file of a local build.
+	pub fn from_file(path: &Path) -> std::io::Result<Self> {
+		let code = fs::read(path)?;
+		info!(target: LOG_TARGET, "using runtime of {} bytes from {:?}", code.len(), path);
+		Ok(Self::new(code, None))
+	}
+
+	/// Call the runtime api `method`, e.g. `Core_version`, with the scale encoded `data`, and
+	/// return the scale encoded result.
+	///
+	/// The storage changes made by the call are kept in `ext`.
+	pub fn call(
+		&self,
+		ext: &mut TestExternalities,
+		method: &str,
+		data: &[u8],
+	) -> Result<Vec<u8>, Error> {
+		debug!(target: LOG_TARGET, "calling {} with {} bytes", method, data.len());
+		self.executor
+			.call_in_wasm(
+				&self.code,
+				Some(self.code_hash.clone()),
+				method,
+				data,
+				&mut ext.ext(),
+				MissingHostFunctions::Allow,
+			)
+			.map_err(|reason| Error::Runtime { method: method.to_string(), reason })
+	}
+
+	/// Call `method`, and decode its result as `R`.
+	pub fn call_decoded<R: Decode>(
+		&self,
+		ext: &mut TestExternalities,
+		method: &str,
+		data: &[u8],
+	) -> Result<R, Error> {
+		let result = self.call(ext, method, data)?;
+		R::decode(&mut &*result).map_err(|e| Error::Runtime {
+			method: method.to_string(),
+			reason: format!("failed to decode the result: {}", e),
+		})
+	}
+
+	/// The version of the runtime, via `Core_version`.
+	pub fn version(&self, ext: &mut TestExternalities) -> Result<RuntimeVersion, Error> {
+		self.call_decoded(ext, "Core_version", &[])
+	}
+
+	/// The raw, scale encoded, metadata of the runtime, via `Metadata_metadata`.
+	///
+	/// This can be decoded with `sub_storage::metadata::Metadata::decode`.
+	pub fn metadata(&self, ext: &mut TestExternalities) -> Result<Vec<u8>, Error> {
+		// the api returns `OpaqueMetadata`, i.e. the metadata encoded a second time.
+		self.call_decoded(ext, "Metadata_metadata", &[])
+	}
+}
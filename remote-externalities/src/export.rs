@@ -0,0 +1,138 @@
+//! Exporting a scraped state, e.g. to spin up a local dev node from it.
+//!
+//! Two formats are supported:
+//!
+//! - [`chain_spec`]: a raw chain spec, where all the pairs are in `genesis.raw.top`.
+//! - [`readable`]: a human readable dump, where the pairs are grouped by module and storage item,
+//!   as far as they can be identified with the given metadata.
+//!
+//! Both can be created from a [`crate::Snapshot`], or from the state of [`TestExternalities`] after
+//! execution, see [`pairs_of`].
+
+use crate::{KeyPair, TestExternalities};
+use serde_json::{json, Map, Value};
+use sp_core::{
+	hexdisplay::HexDisplay,
+	storage::{StorageData, StorageKey},
+};
+use sp_state_machine::Backend as _;
+use std::{fs, io, path::Path};
+use sub_storage::metadata::{Metadata, StorageLayout};
+
+/// The key under which pairs that could not be identified are grouped in [`readable`].
+pub const UNKNOWN: &str = "Unknown";
+
+fn hex(data: &[u8]) -> String {
+	format!("0x{}", HexDisplay::from(&data))
+}
+
+/// All the pairs of `ext`, including the changes that are not yet committed.
+pub fn pairs_of(ext: &TestExternalities) -> Vec<KeyPair> {
+	ext.as_backend().pairs().into_iter().map(|(k, v)| (StorageKey(k), StorageData(v))).collect()
+}
+
+/// The content of `genesis.raw.top`, i.e. a map of hex encoded keys to hex encoded values.
+pub fn raw_top(pairs: &[KeyPair]) -> Value {
+	Value::Object(pairs.iter().map(|(k, v)| (hex(&k.0), Value::String(hex(&v.0)))).collect())
+}
+
+/// A raw chain spec with the given `name` and `id`, whose genesis state is `pairs`.
+///
+/// The spec is of type `Development`, with no boot nodes. Those can be edited afterwards, if
+/// needed.
+pub fn chain_spec(name: &str, id: &str, pairs: &[KeyPair]) -> Value {
+	json!({
+		"name": name,
+		"id": id,
+		"chainType": "Development",
+		"bootNodes": [],
+		"telemetryEndpoints": null,
+		"protocolId": null,
+		"properties": null,
+		"consensusEngine": null,
+		"genesis": {
+			"raw": {
+				"top": raw_top(pairs),
+				"childrenDefault": {},
+			}
+		}
+	})
+}
+
+/// A human readable dump of `pairs`.
+///
+/// The result is an object of modules, each being an object of storage items. Plain storage items
+/// map to their value, and maps map to an object of the hex encoded key suffix (i.e. the hashed
+/// keys) to the value. Values are always hex encoded, since their types are only known by name.
+///
+/// Pairs that cannot be identified, or all of them if `metadata` is `None`, are placed under
+/// [`UNKNOWN`], with their full key.
+pub fn readable(pairs: &[KeyPair], metadata: Option<&Metadata>) -> Value {
+	let mut modules = Map::new();
+	let mut unknown = Map::new();
+
+	for (k, v) in pairs {
+		let value = Value::String(hex(&v.0));
+		match metadata.and_then(|m| m.storage_of_key(&k.0)) {
+			Some(info) => {
+				let module = modules
+					.entry(info.module_prefix.clone())
+					.or_insert_with(|| Value::Object(Map::new()))
+					.as_object_mut()
+					.expect("modules are always objects; qed");
+				match info.layout {
+					StorageLayout::Plain { .. } => {
+						module.insert(info.name.clone(), value);
+					}
+					StorageLayout::Map { .. } | StorageLayout::DoubleMap { .. } => {
+						module
+							.entry(info.name.clone())
+							.or_insert_with(|| Value::Object(Map::new()))
+							.as_object_mut()
+							.expect("maps are always objects; qed")
+							.insert(hex(&k.0[info.prefix.len()..]), value);
+					}
+				}
+			}
+			None => {
+				unknown.insert(hex(&k.0), value);
+			}
+		}
+	}
+
+	if !unknown.is_empty() {
+		modules.insert(UNKNOWN.into(), Value::Object(unknown));
+	}
+	Value::Object(modules)
+}
+
+/// Write the given json value to `path`, pretty printed.
+pub fn write_json(path: &Path, value: &Value) -> io::Result<()> {
+	let content = serde_json::to_string_pretty(value).map_err(io::Error::from)?;
+	fs::write(path, content)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn export_works() {
+		let pairs = vec![
+			(StorageKey(vec![1, 2]), StorageData(vec![3])),
+			(StorageKey(vec![10]), StorageData(vec![])),
+		];
+		let spec = chain_spec("Local Kusama", "local_kusama", &pairs);
+		assert_eq!(spec["genesis"]["raw"]["top"]["0x0102"], "0x03");
+		assert_eq!(spec["genesis"]["raw"]["top"]["0x0a"], "0x");
+		assert_eq!(spec["id"], "local_kusama");
+
+		let readable = readable(&pairs, None);
+		assert_eq!(readable[UNKNOWN]["0x0102"], "0x03");
+
+		let mut ext = TestExternalities::new_empty();
+		pairs.iter().for_each(|(k, v)| ext.insert(k.0.clone(), v.0.clone()));
+		ext.execute_with(|| sp_io::storage::set(&[1, 2], &[4]));
+		assert!(pairs_of(&ext).contains(&(StorageKey(vec![1, 2]), StorageData(vec![4]))));
+	}
+}
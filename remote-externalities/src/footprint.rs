@@ -0,0 +1,485 @@
+//! Recording the storage accesses of code executed in externalities.
+//!
+//! When debugging weights or proof sizes, it is useful to know which keys a piece of code touches.
+//! [`execute_recorded`], and [`crate::LazyExternalities::execute_recorded`], run a closure while a
+//! [`RecordingBackend`] records every read that reaches the backend, of the main trie and of child
+//! tries alike, and return a [`Footprint`] with all the accesses, including the keys that were
+//! written:
+//!
+//! ```ignore
+//! let mut ext = Builder::new().module("Staking").build().await?;
+//! let (_, footprint) = footprint::execute_recorded(&mut ext, || {
+//!     <pallet_staking::Module<Runtime>>::on_initialize(1);
+//! });
+//! println!("{}", footprint.report(Some(&metadata)));
+//! ```
+//!
+//! Reads that are answered by the overlay, i.e. of keys that were already written, never reach the
+//! backend and are thus not recorded, just like they are not part of a storage proof.
+
+use crate::{library::item_of, Hash, TestExternalities, LOG_TARGET};
+use log::*;
+use sp_core::{
+	hexdisplay::HexDisplay,
+	storage::{ChildInfo, StorageKey},
+};
+use sp_externalities::Extensions;
+use sp_runtime::traits::BlakeTwo256;
+use sp_state_machine::{Backend, Ext, OverlayedChanges, StorageTransactionCache, UsageInfo};
+use std::{
+	cell::RefCell,
+	collections::{BTreeMap, BTreeSet},
+	fmt,
+};
+use sub_storage::metadata::Metadata;
+
+/// The kind of a storage access.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd)]
+pub enum AccessKind {
+	/// The value was read from the backend.
+	Read,
+	/// The key was enumerated, i.e. found as the next key of another, or while iterating over the
+	/// keys of a child trie, without reading its value.
+	Enumerate,
+	/// The value was set, or removed.
+	Write,
+}
+
+/// A single storage access.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Access {
+	/// The accessed key.
+	pub key: Vec<u8>,
+	/// The prefixed storage key of the child trie of `key`, if it is not in the main trie.
+	pub child: Option<Vec<u8>>,
+	/// The size of the value read or written, or `None` if the key does not exist, was removed, or
+	/// was only enumerated.
+	pub size: Option<usize>,
+	/// Read, enumerate or write.
+	pub kind: AccessKind,
+	/// True if this is the first access of this kind to the key.
+	///
+	/// Writes are the net changes of the execution, one per written key, and thus always first.
+	pub first: bool,
+}
+
+/// All the storage accesses of an execution.
+///
+/// Reads and enumerations are in the order in which they happened, followed by one write per
+/// written key, with its final value.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Footprint {
+	/// The accesses.
+	pub accesses: Vec<Access>,
+}
+
+impl Footprint {
+	/// All the reads.
+	pub fn reads(&self) -> impl Iterator<Item = &Access> {
+		self.accesses.iter().filter(|a| a.kind == AccessKind::Read)
+	}
+
+	/// All the writes.
+	pub fn writes(&self) -> impl Iterator<Item = &Access> {
+		self.accesses.iter().filter(|a| a.kind == AccessKind::Write)
+	}
+
+	/// The accesses grouped by `(module, item)`, identified with `metadata` if given.
+	///
+	/// The accesses to a child trie are grouped under the item of its storage key.
+	pub fn report(&self, metadata: Option<&Metadata>) -> FootprintReport {
+		let mut items = BTreeMap::<_, ItemFootprint>::new();
+		for access in &self.accesses {
+			let key = access.child.as_ref().unwrap_or(&access.key);
+			let item = items.entry(item_of(&StorageKey(key.clone()), metadata)).or_default();
+			let size = access.size.unwrap_or_default();
+			match (access.kind, access.first) {
+				(AccessKind::Read, true) => {
+					item.reads += 1;
+					item.bytes_read += access.key.len() + size;
+				}
+				(AccessKind::Read, false) => item.repeated_reads += 1,
+				(AccessKind::Enumerate, first) => {
+					item.enumerated += 1;
+					if first {
+						item.bytes_read += access.key.len();
+					}
+				}
+				(AccessKind::Write, _) => {
+					item.writes += 1;
+					item.bytes_written += size;
+				}
+			}
+		}
+		FootprintReport { items }
+	}
+
+	/// An estimate of the size of the storage proof of the execution, in bytes.
+	///
+	/// See [`FootprintReport::proof_size`].
+	pub fn proof_size(&self) -> usize {
+		self.report(None).proof_size()
+	}
+}
+
+/// The accesses to a single storage item.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct ItemFootprint {
+	/// The number of distinct keys read.
+	pub reads: usize,
+	/// The number of reads of keys that had already been read.
+	pub repeated_reads: usize,
+	/// The number of keys enumerated, without reading their values.
+	pub enumerated: usize,
+	/// The total size of the distinct keys read or enumerated, and of the values read.
+	pub bytes_read: usize,
+	/// The number of keys written.
+	pub writes: usize,
+	/// The total size of the written values.
+	pub bytes_written: usize,
+}
+
+impl std::ops::AddAssign for ItemFootprint {
+	fn add_assign(&mut self, other: Self) {
+		self.reads += other.reads;
+		self.repeated_reads += other.repeated_reads;
+		self.enumerated += other.enumerated;
+		self.bytes_read += other.bytes_read;
+		self.writes += other.writes;
+		self.bytes_written += other.bytes_written;
+	}
+}
+
+impl fmt::Display for ItemFootprint {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(
+			f,
+			"{} reads ({} repeated), {} keys enumerated, {} bytes read, {} writes, {} bytes \
+			 written",
+			self.reads,
+			self.repeated_reads,
+			self.enumerated,
+			self.bytes_read,
+			self.writes,
+			self.bytes_written,
+		)
+	}
+}
+
+/// A [`Footprint`], grouped by storage item, see [`Footprint::report`].
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct FootprintReport {
+	/// The accesses of each `(module, item)`.
+	pub items: BTreeMap<(String, String), ItemFootprint>,
+}
+
+impl FootprintReport {
+	/// The accesses of all items.
+	pub fn total(&self) -> ItemFootprint {
+		let mut total = ItemFootprint::default();
+		self.items.values().for_each(|item| total += *item);
+		total
+	}
+
+	/// An estimate of the size of the storage proof, in bytes.
+	///
+	/// This is the size of all the distinct keys read, and of their values. The trie nodes on the
+	/// path to each key are not accounted for, so the real proof is larger.
+	pub fn proof_size(&self) -> usize {
+		self.total().bytes_read
+	}
+}
+
+impl fmt::Display for FootprintReport {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let mut items = self.items.iter().collect::<Vec<_>>();
+		items.sort_by_key(|(_, item)| std::cmp::Reverse(item.bytes_read));
+		for ((module, item), footprint) in items {
+			writeln!(f, "{}::{}: {}", module, item, footprint)?;
+		}
+		writeln!(f, "total: {}", self.total())?;
+		writeln!(f, "estimated proof size: {} bytes", self.proof_size())
+	}
+}
+
+/// A storage backend that records all the reads of another backend.
+#[derive(Debug)]
+pub struct RecordingBackend<'a, B> {
+	inner: &'a B,
+	/// All the distinct accesses so far, as `(kind, child, key)`.
+	seen: RefCell<BTreeSet<(AccessKind, Option<Vec<u8>>, Vec<u8>)>>,
+	accesses: RefCell<Vec<Access>>,
+}
+
+impl<'a, B> RecordingBackend<'a, B> {
+	/// Record the reads of `inner`.
+	pub fn new(inner: &'a B) -> Self {
+		Self { inner, seen: Default::default(), accesses: Default::default() }
+	}
+
+	/// All the reads recorded so far.
+	pub fn into_accesses(self) -> Vec<Access> {
+		self.accesses.into_inner()
+	}
+
+	fn record(
+		&self,
+		kind: AccessKind,
+		child_info: Option<&ChildInfo>,
+		key: &[u8],
+		size: Option<usize>,
+	) {
+		trace!(target: LOG_TARGET, "{:?} {:?}", kind, HexDisplay::from(&key));
+		let child = child_info.map(|info| info.prefixed_storage_key().into_inner());
+		let first = self.seen.borrow_mut().insert((kind, child.clone(), key.to_vec()));
+		self.accesses.borrow_mut().push(Access { key: key.to_vec(), child, size, kind, first });
+	}
+}
+
+impl<'a, B: Backend<BlakeTwo256>> Backend<BlakeTwo256> for RecordingBackend<'a, B> {
+	type Error = B::Error;
+	type Transaction = B::Transaction;
+	type TrieBackendStorage = B::TrieBackendStorage;
+
+	fn storage(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+		let value = self.inner.storage(key)?;
+		self.record(AccessKind::Read, None, key, value.as_ref().map(|v| v.len()));
+		Ok(value)
+	}
+
+	fn child_storage(
+		&self,
+		child_info: &ChildInfo,
+		key: &[u8],
+	) -> Result<Option<Vec<u8>>, Self::Error> {
+		let value = self.inner.child_storage(child_info, key)?;
+		self.record(AccessKind::Read, Some(child_info), key, value.as_ref().map(|v| v.len()));
+		Ok(value)
+	}
+
+	fn next_storage_key(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+		let next = self.inner.next_storage_key(key)?;
+		if let Some(next) = &next {
+			self.record(AccessKind::Enumerate, None, next, None);
+		}
+		Ok(next)
+	}
+
+	fn next_child_storage_key(
+		&self,
+		child_info: &ChildInfo,
+		key: &[u8],
+	) -> Result<Option<Vec<u8>>, Self::Error> {
+		let next = self.inner.next_child_storage_key(child_info, key)?;
+		if let Some(next) = &next {
+			self.record(AccessKind::Enumerate, Some(child_info), next, None);
+		}
+		Ok(next)
+	}
+
+	fn for_key_values_with_prefix<F: FnMut(&[u8], &[u8])>(&self, prefix: &[u8], mut f: F) {
+		self.inner.for_key_values_with_prefix(prefix, |key, value| {
+			self.record(AccessKind::Read, None, key, Some(value.len()));
+			f(key, value)
+		})
+	}
+
+	fn apply_to_child_keys_while<F: FnMut(&[u8]) -> bool>(&self, child_info: &ChildInfo, mut f: F) {
+		self.inner.apply_to_child_keys_while(child_info, |key| {
+			self.record(AccessKind::Enumerate, Some(child_info), key, None);
+			f(key)
+		})
+	}
+
+	fn for_child_keys_with_prefix<F: FnMut(&[u8])>(
+		&self,
+		child_info: &ChildInfo,
+		prefix: &[u8],
+		mut f: F,
+	) {
+		self.inner.for_child_keys_with_prefix(child_info, prefix, |key| {
+			self.record(AccessKind::Enumerate, Some(child_info), key, None);
+			f(key)
+		})
+	}
+
+	fn storage_root<'b>(
+		&self,
+		delta: impl Iterator<Item = (&'b [u8], Option<&'b [u8]>)>,
+	) -> (Hash, Self::Transaction) {
+		self.inner.storage_root(delta)
+	}
+
+	fn child_storage_root<'b>(
+		&self,
+		child_info: &ChildInfo,
+		delta: impl Iterator<Item = (&'b [u8], Option<&'b [u8]>)>,
+	) -> (Hash, bool, Self::Transaction) {
+		self.inner.child_storage_root(child_info, delta)
+	}
+
+	fn pairs(&self) -> Vec<(Vec<u8>, Vec<u8>)> {
+		self.inner.pairs()
+	}
+
+	fn register_overlay_stats(&mut self, _: &sp_state_machine::StateMachineStats) {}
+
+	fn usage_info(&self) -> UsageInfo {
+		self.inner.usage_info()
+	}
+}
+
+/// The values of all the keys changed in `overlay`, `None` if removed.
+fn overlay_values(overlay: &OverlayedChanges) -> BTreeMap<Vec<u8>, Option<Vec<u8>>> {
+	overlay.changes().map(|(key, value)| (key.clone(), value.value().cloned())).collect()
+}
+
+/// Execute `execute` with the given parts of externalities, recording the reads of `backend` and
+/// the net writes to `overlay`.
+pub(crate) fn execute_with_recording<B: Backend<BlakeTwo256>, R>(
+	overlay: &mut OverlayedChanges,
+	storage_transaction_cache: &mut StorageTransactionCache<B::Transaction, BlakeTwo256, u32>,
+	backend: &B,
+	extensions: Option<&mut Extensions>,
+	execute: impl FnOnce() -> R,
+) -> (R, Footprint) {
+	let before = overlay_values(overlay);
+	let recording = RecordingBackend::new(backend);
+	let result = {
+		let mut ext = Ext::new(overlay, storage_transaction_cache, &recording, None, extensions);
+		sp_externalities::set_and_run_with_externalities(&mut ext, execute)
+	};
+
+	let mut accesses = recording.into_accesses();
+	for (key, value) in overlay_values(overlay) {
+		if before.get(&key) != Some(&value) {
+			let size = value.map(|v| v.len());
+			accesses.push(Access { key, child: None, size, kind: AccessKind::Write, first: true });
+		}
+	}
+	(result, Footprint { accesses })
+}
+
+/// Execute `execute` in `ext`, and return the [`Footprint`] of its storage accesses.
+///
+/// The pending changes of `ext` are committed first, so that `execute` reads the backend of `ext`
+/// directly, with the extensions registered in `ext`. Its writes are then applied to `ext`.
+pub fn execute_recorded<R>(
+	ext: &mut TestExternalities,
+	execute: impl FnOnce() -> R,
+) -> (R, Footprint) {
+	ext.commit_all().expect("committing to an in memory backend cannot fail; qed");
+	let mut overlay = OverlayedChanges::default();
+	let (result, footprint) = execute_with_recording(
+		&mut overlay,
+		&mut Default::default(),
+		&ext.backend,
+		Some(&mut ext.extensions),
+		execute,
+	);
+
+	ext.execute_with(|| {
+		for (key, value) in overlay_values(&overlay) {
+			match value {
+				Some(value) => sp_io::storage::set(&key, &value),
+				None => sp_io::storage::clear(&key),
+			}
+		}
+	});
+	(result, footprint)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn footprint_works() {
+		let number = sub_storage::module_prefix_raw(b"System", b"Number");
+		let now = sub_storage::module_prefix_raw(b"Timestamp", b"Now");
+		let mut ext = TestExternalities::new_empty();
+		ext.insert(number.clone(), vec![1, 0, 0, 0]);
+
+		let (_, footprint) = execute_recorded(&mut ext, || {
+			assert_eq!(sp_io::storage::get(&number), Some(vec![1, 0, 0, 0]));
+			assert_eq!(sp_io::storage::get(&number), Some(vec![1, 0, 0, 0]));
+			assert_eq!(sp_io::storage::get(&now), None);
+			sp_io::storage::set(&now, &[7; 8]);
+			// answered by the overlay, thus not a read of the backend.
+			assert_eq!(sp_io::storage::get(&now), Some(vec![7; 8]));
+		});
+
+		assert_eq!(footprint.reads().count(), 3);
+		assert_eq!(footprint.writes().count(), 1);
+		let report = footprint.report(None);
+		let item_of = |key: &[u8]| crate::library::item_of(&StorageKey(key.to_vec()), None);
+		assert_eq!(
+			report.items[&item_of(&number)],
+			ItemFootprint { reads: 1, repeated_reads: 1, bytes_read: 32 + 4, ..Default::default() }
+		);
+		assert_eq!(
+			report.items[&item_of(&now)],
+			ItemFootprint {
+				reads: 1,
+				bytes_read: 32,
+				writes: 1,
+				bytes_written: 8,
+				..Default::default()
+			}
+		);
+		assert_eq!(footprint.proof_size(), 32 + 4 + 32);
+		assert!(report.to_string().contains("estimated proof size: 68 bytes"));
+
+		// the writes are kept in the externalities.
+		ext.execute_with(|| assert_eq!(sp_io::storage::get(&now), Some(vec![7; 8])));
+	}
+
+	#[test]
+	fn child_and_next_key_accesses_are_recorded() {
+		use sp_externalities::ExternalitiesExt;
+		use sp_keystore::{testing::KeyStore, KeystoreExt};
+
+		let child = ChildInfo::new_default(b"child");
+		let mut ext = TestExternalities::new_empty();
+		ext.insert(vec![1], vec![1]);
+		ext.insert(vec![2], vec![2]);
+		ext.execute_with(|| sp_io::default_child_storage::set(b"child", b"key", b"value"));
+		ext.register_extension(KeystoreExt(std::sync::Arc::new(KeyStore::new())));
+
+		let (_, footprint) = execute_recorded(&mut ext, || {
+			assert_eq!(sp_io::storage::next_key(&[1]), Some(vec![2]));
+			assert_eq!(
+				sp_io::default_child_storage::get(b"child", b"key"),
+				Some(b"value".to_vec())
+			);
+			sp_io::storage::set(&[3], &[3]);
+			sp_io::storage::set(&[3], &[3, 3]);
+			// the extensions of `ext` are available.
+			let keystore = |mut ext: &mut dyn sp_externalities::Externalities| {
+				ext.extension::<KeystoreExt>().is_some()
+			};
+			assert_eq!(sp_externalities::with_externalities(keystore), Some(true));
+		});
+
+		let access = |key: &[u8], child: Option<&ChildInfo>, size, kind| Access {
+			key: key.to_vec(),
+			child: child.map(|info| info.prefixed_storage_key().into_inner()),
+			size,
+			kind,
+			first: true,
+		};
+		assert!(footprint.accesses.contains(&access(&[2], None, None, AccessKind::Enumerate)));
+		assert!(footprint.accesses.contains(&access(
+			b"key",
+			Some(&child),
+			Some(5),
+			AccessKind::Read
+		)));
+		// only the final value of a key written twice.
+		assert_eq!(
+			footprint.writes().collect::<Vec<_>>(),
+			vec![&access(&[3], None, Some(2), AccessKind::Write)]
+		);
+		ext.execute_with(|| assert_eq!(sp_io::storage::get(&[3]), Some(vec![3, 3])));
+	}
+}
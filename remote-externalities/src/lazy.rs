@@ -0,0 +1,587 @@
+//! Lazy, on-demand, remote externalities.
+//!
+//! Instead of downloading the state upfront, [`LazyBackend`] starts empty and fetches every key
+//! that is read, and misses, from the node at the pinned block. Iteration (`next_key`) is also
+//! answered by the node, a page of keys, and their values, at a time. Keys of child tries are
+//! fetched the same way, but iterating a child trie fetches all of it at once. All fetched keys are
+//! recorded, so that the minimal state that a test needs can be saved as a snapshot (see
+//! [`LazyExternalities::save`]) and loaded offline in later runs.
+//!
+//! Since storage reads are synchronous, all the requests are sent to a dedicated worker thread that
+//! owns the rpc client and its own runtime. A failed request fails the storage access, so that the
+//! externalities panic with the failed method, key and reason.
+
+use crate::{
+	footprint::Footprint, Hash, HexDisplayExt, KeyPair, RpcClient, Snapshot, SnapshotError,
+	SnapshotHeader, LOG_TARGET,
+};
+use jsonrpsee_types::jsonrpc::{from_value, to_value as to_json_value, JsonValue, Params};
+use log::*;
+use sp_core::storage::{ChildInfo, StorageChangeSet, StorageData, StorageKey};
+use sp_externalities::{Extension, Extensions};
+use sp_runtime::traits::BlakeTwo256;
+use sp_state_machine::{
+	Backend, Ext, InMemoryBackend, OverlayedChanges, StorageTransactionCache, UsageInfo,
+};
+use std::{
+	cell::RefCell,
+	collections::{BTreeMap, BTreeSet},
+	path::Path,
+	sync::mpsc::{channel, Sender},
+	thread,
+};
+
+type Inner = InMemoryBackend<BlakeTwo256>;
+type Reply = Sender<Result<JsonValue, String>>;
+/// The error of the backend, i.e. a description of the failed request.
+type BackendError = <Inner as Backend<BlakeTwo256>>::Error;
+
+/// Number of keys requested per `state_getKeysPaged` call, when a whole prefix is enumerated, or
+/// when iterating with `next_key`.
+const PAGE_SIZE: u32 = 1000;
+
+/// A handle to the thread that talks to the node.
+#[derive(Debug)]
+struct Worker {
+	requests: Sender<(&'static str, Vec<JsonValue>, Reply)>,
+}
+
+impl Worker {
+	/// Spawn a worker that sends all the requests to the node at `uri`.
+	fn spawn(uri: String) -> Self {
+		Self::spawn_with(move || {
+			let runtime = tokio::runtime::Builder::new_current_thread()
+				.enable_all()
+				.build()
+				.expect("failed to create the runtime of the lazy backend");
+			let client = runtime.block_on(RpcClient::new(&uri));
+			move |method: &'static str, params: Vec<JsonValue>| match client.as_ref() {
+				Ok(client) => runtime
+					.block_on(client.request::<JsonValue>(method, Params::Array(params)))
+					.map_err(|e| e.to_string()),
+				Err(e) => Err(e.to_string()),
+			}
+		})
+	}
+
+	/// Spawn a worker that answers all the requests with the handler returned by `init`, which is
+	/// called on the worker thread.
+	fn spawn_with<I, H>(init: I) -> Self
+	where
+		I: FnOnce() -> H + Send + 'static,
+		H: FnMut(&'static str, Vec<JsonValue>) -> Result<JsonValue, String>,
+	{
+		let (requests, incoming) = channel::<(&'static str, Vec<JsonValue>, Reply)>();
+		thread::Builder::new()
+			.name("remote-ext-lazy".into())
+			.spawn(move || {
+				let mut handle = init();
+				// exits once the backend, and thus the sender, is dropped.
+				while let Ok((method, params, reply)) = incoming.recv() {
+					let _ = reply.send(handle(method, params));
+				}
+			})
+			.expect("failed to spawn the worker of the lazy backend");
+		Self { requests }
+	}
+
+	/// Send a request to `method` about `key`, and decode its response.
+	///
+	/// The error names the method and the key, since it ends up in the panic message of the
+	/// externalities.
+	fn call<T: serde::de::DeserializeOwned>(
+		&self,
+		method: &'static str,
+		key: &[u8],
+		params: Vec<JsonValue>,
+	) -> Result<T, BackendError> {
+		let error = |why: &dyn std::fmt::Display| {
+			format!("lazy backend: {} for {:?} failed: {}", method, key.hex_display(), why)
+		};
+		let (reply, response) = channel();
+		self.requests.send((method, params, reply)).expect("worker is alive; qed");
+		let json_value =
+			response.recv().expect("worker always replies; qed").map_err(|e| error(&e))?;
+		from_value(json_value).map_err(|e| error(&e))
+	}
+}
+
+/// Unwrap the result of a request in a backend method that cannot return errors.
+fn or_panic<T>(result: Result<T, BackendError>) -> T {
+	result.unwrap_or_else(|error| panic!("{}", error))
+}
+
+/// A storage backend that fetches missing keys from a remote node.
+#[derive(Debug)]
+pub struct LazyBackend {
+	worker: Worker,
+	at: Hash,
+	/// All the fetched state. Keys that do not exist remotely are not in here.
+	inner: RefCell<Inner>,
+	/// All the keys that have been fetched, including the ones that did not exist.
+	fetched: RefCell<BTreeSet<Vec<u8>>>,
+	/// The answers of the node to `next_key` queries.
+	next_keys: RefCell<BTreeMap<Vec<u8>, Option<Vec<u8>>>>,
+	/// The prefixes that have been fully enumerated.
+	prefixes: RefCell<BTreeSet<Vec<u8>>>,
+	/// All the keys of child tries that have been fetched, as `(prefixed child key, key)`.
+	child_fetched: RefCell<BTreeSet<(Vec<u8>, Vec<u8>)>>,
+	/// The prefixed keys of the child tries that have been fully fetched.
+	children: RefCell<BTreeSet<Vec<u8>>>,
+}
+
+impl LazyBackend {
+	/// Create a new lazy backend that reads from the node at `uri`, at block `at`.
+	pub fn new(uri: String, at: Hash) -> Self {
+		Self::with_worker(Worker::spawn(uri), at)
+	}
+
+	fn with_worker(worker: Worker, at: Hash) -> Self {
+		Self {
+			worker,
+			at,
+			inner: Default::default(),
+			fetched: Default::default(),
+			next_keys: Default::default(),
+			prefixes: Default::default(),
+			child_fetched: Default::default(),
+			children: Default::default(),
+		}
+	}
+
+	fn at_param(&self) -> JsonValue {
+		to_json_value(self.at).expect("Block hash serialization infallible")
+	}
+
+	fn key_param(key: &[u8]) -> JsonValue {
+		to_json_value(StorageKey(key.to_vec())).expect("StorageKey serialization infallible")
+	}
+
+	/// Record the given values, that have just been fetched.
+	fn record(&self, values: Vec<(Vec<u8>, Option<Vec<u8>>)>) {
+		let mut fetched = self.fetched.borrow_mut();
+		let mut insertions = vec![];
+		for (key, maybe_value) in values {
+			if fetched.insert(key.clone()) {
+				if let Some(value) = maybe_value {
+					insertions.push((key, Some(value)));
+				}
+			}
+		}
+		if !insertions.is_empty() {
+			self.inner.borrow_mut().insert(vec![(None, insertions)]);
+		}
+	}
+
+	/// Make sure `key` has been fetched.
+	fn ensure(&self, key: &[u8]) -> Result<(), BackendError> {
+		if self.fetched.borrow().contains(key) {
+			return Ok(());
+		}
+		trace!(target: LOG_TARGET, "fetching {:?}", key.hex_display());
+		let maybe_value: Option<StorageData> = self.worker.call(
+			"state_getStorage",
+			key,
+			vec![Self::key_param(key), self.at_param()],
+		)?;
+		self.record(vec![(key.to_vec(), maybe_value.map(|v| v.0))]);
+		Ok(())
+	}
+
+	/// Make sure all the given `keys` have been fetched, in batches.
+	fn ensure_all(&self, keys: &[StorageKey]) -> Result<(), BackendError> {
+		let missing =
+			keys.iter().filter(|k| !self.fetched.borrow().contains(&k.0)).collect::<Vec<_>>();
+		for batch in missing.chunks(crate::DEFAULT_BATCH_SIZE) {
+			let keys_param = to_json_value(batch).expect("StorageKey serialization infallible");
+			let change_sets: Vec<StorageChangeSet<Hash>> = self.worker.call(
+				"state_queryStorageAt",
+				&batch[0].0,
+				vec![keys_param, self.at_param()],
+			)?;
+			let values = change_sets
+				.into_iter()
+				.flat_map(|set| set.changes)
+				.map(|(k, v)| (k.0, v.map(|v| v.0)))
+				.collect();
+			self.record(values);
+		}
+		Ok(())
+	}
+
+	/// Get a page of at most [`PAGE_SIZE`] keys under `prefix`, after `start_key` if given.
+	fn keys_page(
+		&self,
+		prefix: &[u8],
+		start_key: Option<&[u8]>,
+	) -> Result<Vec<StorageKey>, BackendError> {
+		let start_param = start_key.map(Self::key_param).unwrap_or(JsonValue::Null);
+		self.worker.call(
+			"state_getKeysPaged",
+			start_key.unwrap_or(prefix),
+			vec![Self::key_param(prefix), PAGE_SIZE.into(), start_param, self.at_param()],
+		)
+	}
+
+	/// Make sure all the keys under `prefix` have been fetched.
+	fn ensure_prefix(&self, prefix: &[u8]) -> Result<(), BackendError> {
+		if self.prefixes.borrow().iter().any(|p| prefix.starts_with(p)) {
+			return Ok(());
+		}
+		debug!(target: LOG_TARGET, "fetching all keys under {:?}", prefix.hex_display());
+
+		let mut keys: Vec<StorageKey> = vec![];
+		loop {
+			let page = self.keys_page(prefix, keys.last().map(|k| &k.0[..]))?;
+			let page_len = page.len();
+			keys.extend(page);
+			if page_len < PAGE_SIZE as usize {
+				break;
+			}
+		}
+
+		self.ensure_all(&keys)?;
+		self.prefixes.borrow_mut().insert(prefix.to_vec());
+		Ok(())
+	}
+
+	/// The key that follows `key`, if known from the pages fetched so far.
+	///
+	/// Each entry `a => b` of `next_keys` means that no key exists between `a` and `b`, so it also
+	/// answers any key in `[a, b)`.
+	fn cached_next_key(&self, key: &[u8]) -> Option<Option<Vec<u8>>> {
+		let next_keys = self.next_keys.borrow();
+		let (_, next) = next_keys.range::<[u8], _>(..=key).next_back()?;
+		match next {
+			Some(next) if next.as_slice() <= key => None,
+			next => Some(next.clone()),
+		}
+	}
+
+	/// Fetch the page of keys that follow `key`, with their values, and cache the order of keys.
+	fn fetch_next_keys(&self, key: &[u8]) -> Result<Option<Vec<u8>>, BackendError> {
+		trace!(target: LOG_TARGET, "fetching the keys after {:?}", key.hex_display());
+		let page = self.keys_page(&[], Some(key))?;
+		self.ensure_all(&page)?;
+
+		let mut next_keys = self.next_keys.borrow_mut();
+		let mut previous = key.to_vec();
+		for next in &page {
+			next_keys
+				.insert(std::mem::replace(&mut previous, next.0.clone()), Some(next.0.clone()));
+		}
+		if page.len() < PAGE_SIZE as usize {
+			next_keys.insert(previous, None);
+		}
+		Ok(page.first().map(|k| k.0.clone()))
+	}
+
+	fn child_key_param(child_info: &ChildInfo) -> JsonValue {
+		Self::key_param(&child_info.prefixed_storage_key().into_inner())
+	}
+
+	/// Fetch `key` of the given child trie, if not fetched yet.
+	fn ensure_child(&self, child_info: &ChildInfo, key: &[u8]) -> Result<(), BackendError> {
+		let child_key = child_info.prefixed_storage_key().into_inner();
+		if self.child_fetched.borrow().contains(&(child_key.clone(), key.to_vec())) {
+			return Ok(());
+		}
+		trace!(target: LOG_TARGET, "fetching child {:?}", key.hex_display());
+		let maybe_value: Option<StorageData> = self.worker.call(
+			"childstate_getStorage",
+			key,
+			vec![Self::child_key_param(child_info), Self::key_param(key), self.at_param()],
+		)?;
+		self.child_fetched.borrow_mut().insert((child_key, key.to_vec()));
+		if let Some(value) = maybe_value {
+			self.inner
+				.borrow_mut()
+				.insert(vec![(Some(child_info.clone()), vec![(key.to_vec(), Some(value.0))])]);
+		}
+		Ok(())
+	}
+
+	/// Make sure the whole given child trie has been fetched.
+	fn ensure_child_trie(&self, child_info: &ChildInfo) -> Result<(), BackendError> {
+		let child_key = child_info.prefixed_storage_key().into_inner();
+		if self.children.borrow().contains(&child_key) {
+			return Ok(());
+		}
+		debug!(target: LOG_TARGET, "fetching child trie {:?}", child_key.hex_display());
+		let keys: Vec<StorageKey> = self.worker.call(
+			"childstate_getKeys",
+			&child_key,
+			vec![Self::child_key_param(child_info), Self::key_param(&[]), self.at_param()],
+		)?;
+		for key in keys {
+			self.ensure_child(child_info, &key.0)?;
+		}
+		self.children.borrow_mut().insert(child_key);
+		Ok(())
+	}
+
+	/// The number of keys fetched so far, including the ones that did not exist.
+	pub fn fetched_count(&self) -> usize {
+		self.fetched.borrow().len()
+	}
+
+	/// All the existing key-value pairs fetched so far. Child tries are not included.
+	pub fn recorded(&self) -> Vec<KeyPair> {
+		self.inner
+			.borrow()
+			.pairs()
+			.into_iter()
+			.map(|(k, v)| (StorageKey(k), StorageData(v)))
+			.collect()
+	}
+}
+
+impl Backend<BlakeTwo256> for LazyBackend {
+	type Error = <Inner as Backend<BlakeTwo256>>::Error;
+	type Transaction = <Inner as Backend<BlakeTwo256>>::Transaction;
+	type TrieBackendStorage = <Inner as Backend<BlakeTwo256>>::TrieBackendStorage;
+
+	fn storage(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+		self.ensure(key)?;
+		self.inner.borrow().storage(key)
+	}
+
+	fn child_storage(
+		&self,
+		child_info: &ChildInfo,
+		key: &[u8],
+	) -> Result<Option<Vec<u8>>, Self::Error> {
+		self.ensure_child(child_info, key)?;
+		self.inner.borrow().child_storage(child_info, key)
+	}
+
+	fn next_storage_key(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+		match self.cached_next_key(key) {
+			Some(next) => Ok(next),
+			None => self.fetch_next_keys(key),
+		}
+	}
+
+	fn next_child_storage_key(
+		&self,
+		child_info: &ChildInfo,
+		key: &[u8],
+	) -> Result<Option<Vec<u8>>, Self::Error> {
+		self.ensure_child_trie(child_info)?;
+		self.inner.borrow().next_child_storage_key(child_info, key)
+	}
+
+	fn for_key_values_with_prefix<F: FnMut(&[u8], &[u8])>(&self, prefix: &[u8], f: F) {
+		or_panic(self.ensure_prefix(prefix));
+		self.inner.borrow().for_key_values_with_prefix(prefix, f)
+	}
+
+	fn apply_to_child_keys_while<F: FnMut(&[u8]) -> bool>(&self, child_info: &ChildInfo, f: F) {
+		or_panic(self.ensure_child_trie(child_info));
+		self.inner.borrow().apply_to_child_keys_while(child_info, f)
+	}
+
+	fn for_child_keys_with_prefix<F: FnMut(&[u8])>(
+		&self,
+		child_info: &ChildInfo,
+		prefix: &[u8],
+		f: F,
+	) {
+		or_panic(self.ensure_child_trie(child_info));
+		self.inner.borrow().for_child_keys_with_prefix(child_info, prefix, f)
+	}
+
+	fn storage_root<'a>(
+		&self,
+		delta: impl Iterator<Item = (&'a [u8], Option<&'a [u8]>)>,
+	) -> (Hash, Self::Transaction) {
+		self.inner.borrow().storage_root(delta)
+	}
+
+	fn child_storage_root<'a>(
+		&self,
+		child_info: &ChildInfo,
+		delta: impl Iterator<Item = (&'a [u8], Option<&'a [u8]>)>,
+	) -> (Hash, bool, Self::Transaction) {
+		or_panic(self.ensure_child_trie(child_info));
+		self.inner.borrow().child_storage_root(child_info, delta)
+	}
+
+	/// All the pairs of the top trie.
+	///
+	/// Note that this downloads the whole state of the node, if not fetched yet.
+	fn pairs(&self) -> Vec<(Vec<u8>, Vec<u8>)> {
+		or_panic(self.ensure_prefix(&[]));
+		self.inner.borrow().pairs()
+	}
+
+	fn register_overlay_stats(&mut self, _: &sp_state_machine::StateMachineStats) {}
+
+	fn usage_info(&self) -> UsageInfo {
+		UsageInfo::empty()
+	}
+}
+
+/// Externalities backed by a [`LazyBackend`].
+///
+/// Created by [`crate::Builder::build_lazy`]. Note that the storage root of these externalities is
+/// only the root of the fetched state.
+pub struct LazyExternalities {
+	overlay: OverlayedChanges,
+	storage_transaction_cache: StorageTransactionCache<
+		<LazyBackend as Backend<BlakeTwo256>>::Transaction,
+		BlakeTwo256,
+		u32,
+	>,
+	backend: LazyBackend,
+	extensions: Extensions,
+	header: SnapshotHeader,
+}
+
+impl LazyExternalities {
+	pub(crate) fn new(backend: LazyBackend, header: SnapshotHeader) -> Self {
+		Self {
+			overlay: Default::default(),
+			storage_transaction_cache: Default::default(),
+			backend,
+			extensions: Default::default(),
+			header,
+		}
+	}
+
+	/// Execute the given closure while `self` is set as the externalities.
+	pub fn execute_with<R>(&mut self, execute: impl FnOnce() -> R) -> R {
+		let mut ext = Ext::new(
+			&mut self.overlay,
+			&mut self.storage_transaction_cache,
+			&self.backend,
+			None,
+			Some(&mut self.extensions),
+		);
+		sp_externalities::set_and_run_with_externalities(&mut ext, execute)
+	}
+
+	/// Execute the given closure like [`Self::execute_with`], and return the footprint of its
+	/// storage accesses, see [`crate::footprint`].
+	pub fn execute_recorded<R>(&mut self, execute: impl FnOnce() -> R) -> (R, Footprint) {
+		crate::footprint::execute_with_recording(
+			&mut self.overlay,
+			&mut self.storage_transaction_cache,
+			&self.backend,
+			Some(&mut self.extensions),
+			execute,
+		)
+	}
+
+	/// Register an extension, e.g. a keystore.
+	pub fn register_extension<E: std::any::Any + Extension>(&mut self, ext: E) {
+		self.extensions.register(ext);
+	}
+
+	/// The backend of `self`.
+	pub fn backend(&self) -> &LazyBackend {
+		&self.backend
+	}
+
+	/// Save all the fetched state as a snapshot at `path`.
+	///
+	/// The snapshot can be later used with [`crate::CacheMode::Offline`] to re-run the same test
+	/// without a node. Changes made during execution are not saved.
+	pub fn save(&self, path: &Path) -> Result<(), SnapshotError> {
+		let pairs = self.backend.recorded();
+		info!(target: LOG_TARGET, "saving {} fetched keys to {:?}", pairs.len(), path);
+		let header = SnapshotHeader { created_at: SnapshotHeader::now(), ..self.header.clone() };
+		Snapshot::write(path, &header, &pairs)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::sync::{Arc, Mutex};
+
+	/// A worker that answers from `state`, and records the called methods in `calls`.
+	fn stub_worker(
+		state: BTreeMap<Vec<u8>, Vec<u8>>,
+		calls: Arc<Mutex<Vec<&'static str>>>,
+	) -> Worker {
+		Worker::spawn_with(move || {
+			move |method: &'static str, params: Vec<JsonValue>| {
+				calls.lock().unwrap().push(method);
+				let key = |i: usize| from_value::<StorageKey>(params[i].clone()).unwrap().0;
+				let response = match method {
+					"state_getStorage" => {
+						to_json_value(state.get(&key(0)).cloned().map(StorageData))
+					}
+					"state_getKeysPaged" => {
+						let (prefix, count) =
+							(key(0), from_value::<usize>(params[1].clone()).unwrap());
+						let start = if params[2].is_null() { None } else { Some(key(2)) };
+						let keys = state
+							.keys()
+							.filter(|k| {
+								k.starts_with(&prefix) && start.as_ref().map_or(true, |s| *k > s)
+							})
+							.take(count)
+							.map(|k| StorageKey(k.clone()))
+							.collect::<Vec<_>>();
+						to_json_value(keys)
+					}
+					"state_queryStorageAt" => {
+						let keys = from_value::<Vec<StorageKey>>(params[0].clone()).unwrap();
+						let changes = keys
+							.into_iter()
+							.map(|k| {
+								let value = state.get(&k.0).cloned().map(StorageData);
+								(k, value)
+							})
+							.collect();
+						to_json_value(vec![StorageChangeSet { block: Hash::zero(), changes }])
+					}
+					_ => return Err(format!("{} is not supported", method)),
+				};
+				Ok(response.unwrap())
+			}
+		})
+	}
+
+	#[test]
+	fn next_key_fetches_pages() {
+		let state = (1u8..=5).map(|i| (vec![i], vec![i * 10])).collect::<BTreeMap<_, _>>();
+		let calls = Arc::new(Mutex::new(vec![]));
+		let backend = LazyBackend::with_worker(stub_worker(state, calls.clone()), Hash::zero());
+
+		let mut keys = vec![];
+		let mut key = vec![];
+		while let Some(next) = backend.next_storage_key(&key).unwrap() {
+			keys.push(next.clone());
+			key = next;
+		}
+		assert_eq!(keys, (1u8..=5).map(|i| vec![i]).collect::<Vec<_>>());
+		// a single page of keys, and their values in one batch.
+		assert_eq!(*calls.lock().unwrap(), vec!["state_getKeysPaged", "state_queryStorageAt"]);
+
+		// answered from what was fetched, also for keys that do not exist.
+		assert_eq!(backend.storage(&[3]).unwrap(), Some(vec![30]));
+		assert_eq!(backend.next_storage_key(&[2, 7]).unwrap(), Some(vec![3]));
+		assert_eq!(backend.next_storage_key(&[9]).unwrap(), None);
+		assert_eq!(calls.lock().unwrap().len(), 2);
+		assert_eq!(backend.recorded().len(), 5);
+	}
+
+	#[test]
+	fn failed_requests_are_errors() {
+		let failing = Worker::spawn_with(|| {
+			|_: &'static str, _: Vec<JsonValue>| -> Result<JsonValue, String> {
+				Err("connection reset".into())
+			}
+		});
+		let backend = LazyBackend::with_worker(failing, Hash::zero());
+		let error = backend.storage(&[1, 2]).unwrap_err();
+		assert!(error.contains("state_getStorage"));
+		assert!(error.contains("connection reset"));
+		// nothing is recorded, so the next access tries again.
+		assert_eq!(backend.fetched_count(), 0);
+		assert!(backend.next_storage_key(&[]).unwrap_err().contains("state_getKeysPaged"));
+	}
+}
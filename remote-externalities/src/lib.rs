@@ -92,8 +92,8 @@
 //!     ..
 //! }
 //!
-//! #[test]
-//! fn test_runtime_works() {
+//! #[tokio::test]
+//! async fn test_runtime_works() {
 //!     let hash: Hash =
 //!         hex!["f9a4ce984129569f63edc01b1c13374779f9384f1befd39931ffdcc83acf63a7"].into();
 //!     let parent: Hash =
@@ -102,6 +102,8 @@
 //!         .at(hash)
 //!         .module("System")
 //!         .build()
+//!         .await
+//!         .unwrap()
 //!         .execute_with(|| {
 //!             assert_eq!(
 //!                 // note: the hash corresponds to 3098546. We can check only the parent.
@@ -119,34 +121,279 @@
 //! use remote_externalities::Builder;
 //! use kusama_runtime::Runtime;
 //!
-//! #[test]
-//! fn test_runtime_works() {
+//! #[tokio::test]
+//! async fn test_runtime_works() {
 //!     let hash: Hash =
 //!         hex!["f9a4ce984129569f63edc01b1c13374779f9384f1befd39931ffdcc83acf63a7"].into();
 //!     Builder::new()
 //!         .at(hash)
 //!         .module("Staking")
 //!         .build()
+//!         .await
+//!         .unwrap()
 //!         .execute_with(|| assert_eq!(<pallet_staking::Module<Runtime>>::validator_count(), 400));
 //! }
+//! ```
+//!
+//! ### Caching
+//!
+//! The scraped state can be cached on disk, see [`CacheMode`]. Caches are stored in the directory
+//! given to [`Builder::cache_dir`], else the one in the `REMOTE_EXT_CACHE_DIR` environment
+//! variable, else the current directory. Each cache file starts with a header that records where
+//! its state came from, see [`SnapshotHeader`].
+//!
+//! The pairs themselves are a zstd compressed stream, which is written and read incrementally and
+//! inserted directly into the externalities, so that large states never have to be held in memory
+//! twice. Caches of the older, uncompressed format can still be read.
+//!
+//! A cache can be used without any node via `CacheMode::Offline`, which makes tests reproducible:
+//!
+//! ```ignore
+//! Builder::new()
+//!     .cache_mode(CacheMode::Offline { path: "Kusama,0x7f13..,Staking.bin".into() })
+//!     .build()
+//!     .await?
+//!     .execute_with(|| { .. });
+//! ```
+//!
+//! An existing cache can be updated to a newer block with `CacheMode::Refresh`. The keys are listed
+//! again, new keys are downloaded, and the changes of the others are queried in batches with
+//! `state_queryStorage`. A cache of another chain is an error.
+//!
+//! ### State root verification
+//!
+//! If the entire state is scraped, i.e. no module filter is set, the trie root of the built
+//! externalities is compared with the `state_root` of the block header, so that a truncated or
+//! otherwise incomplete scrape does not go unnoticed. By default, a mismatch makes the build fail
+//! with [`Error::StateRootMismatch`]. See [`Builder::state_root_check`].
+//!
+//! ### Filters
+//!
+//! By default, the entire state is scraped. It can be narrowed down with any combination of
+//! [`Builder::module`], [`Builder::storage_item`], [`Builder::prefix`] and [`Builder::map_keys`],
+//! the latter fetching only given keys of a typed `sub_storage::entry::StorageEntry`. Keys under
+//! [`Builder::exclude`] or [`Builder::exclude_item`] are never scraped, e.g.:
+//!
+//! ```ignore
+//! Builder::new()
+//!     .module("Staking")
+//!     .storage_item("System", "Account")
+//!     .exclude_item("Staking", "ErasStakers")
+//!     .build()
+//!     .await?
+//! ```
+//!
+//! ### Progress
+//!
+//! Scraping a large state takes a while. The progress of downloading values is logged
+//! periodically, with the number of keys and bytes per second, and a final summary is logged per
+//! module, as identified with [`Builder::metadata`] if given. A progress bar can also be drawn on
+//! stderr with [`Builder::progress_bar`], and [`Builder::progress`] passes every [`ProgressReport`]
+//! to a callback, e.g.:
+//!
+//! ```ignore
+//! Builder::new()
+//!     .progress(|report| println!("{:.0}%", report.fraction().unwrap_or_default() * 100.0))
+//!     .build()
+//!     .await?
+//! ```
+//!
+//! ### Overriding storage
+//!
+//! Besides raw [`Builder::inject`], typed values can be injected with [`Builder::inject_value`] and
+//! [`Builder::inject_map`], and keys can be removed with [`Builder::remove_prefix`], e.g. for a
+//! what-if test. The same can be described in a json or yaml file, see [`overrides`]:
+//!
+//! ```ignore
+//! Builder::new()
+//!     .module("Staking")
+//!     .inject_value("Staking", "ValidatorCount", &400u32)
+//!     .inject_map::<Twox64Concat, _, _>("Staking", "Bonded", &stash, &controller)
+//!     .overrides_file("what-if.yaml")
+//!     .build()
+//!     .await?
+//! ```
+//!
+//! ### Lazy mode
+//!
+//! If a test only touches a handful of keys, [`Builder::build_lazy`] creates [`LazyExternalities`]
+//! that start empty, and fetch every key that is read from the node on demand. The fetched keys can
+//! then be saved as a snapshot, and re-used with `CacheMode::Offline`. Keys of child tries are
+//! fetched as well, but are not part of the saved snapshot:
+//!
+//! ```ignore
+//! let mut ext = Builder::new().at(hash).build_lazy().await?;
+//! ext.execute_with(|| { .. });
+//! ext.save("minimal.bin".as_ref()).unwrap();
+//! ```
+//!
+//! ### Block context
+//!
+//! Code executed in externalities of a filtered state sees block number 0 and no timestamp, unless
+//! [`Builder::block_context`] is set. Then, the number, parent hash, digest and timestamp of the
+//! scraped block are set, and saved in the snapshot, so that offline builds from it have them too.
+//! Test extensions can be registered in the built externalities with [`Builder::offchain`],
+//! [`Builder::transaction_pool`] and [`Builder::keystore`]:
+//!
+//! ```ignore
+//! let (pool, pool_state) = TestTransactionPoolExt::new();
+//! Builder::new()
+//!     .module("Staking")
+//!     .block_context()
+//!     .offchain(TestOffchainExt::new().0)
+//!     .transaction_pool(pool)
+//!     .build()
+//!     .await?
+//!     .execute_with(|| { .. });
+//! ```
+//!
+//! ### Executing the runtime
+//!
+//! With the `executor` feature, the real Wasm runtime of the chain can be executed against the
+//! state, instead of compiling a runtime natively as described above. The runtime is read from the
+//! `:code` key of the state, or from a local file, and runtime apis are called by name with scale
+//! encoded arguments, see [`executor::RuntimeExecutor`]:
+//!
+//! ```ignore
+//! let mut ext = Builder::new().build().await?;
+//! let runtime = RuntimeExecutor::from_state(&mut ext)?;
+//! let version = runtime.version(&mut ext)?;
+//! let result = runtime.call(&mut ext, "TryRuntime_on_runtime_upgrade", &[])?;
+//! ```
+//!
+//! ### Exporting
+//!
+//! A scraped state, or the state of externalities after execution, can be exported as a raw chain
+//! spec to start a local dev node from it, or as a human readable json grouped by module and
+//! storage item. See the [`export`] module.
+//!
+//! ### Inspecting changes
+//!
+//! After execution, [`diff::StateDiff`] shows which keys were added, removed or modified compared
+//! to the original snapshot, grouped by storage item if metadata is available. The modified state
+//! can be saved as a new snapshot via [`diff::save_state`], and loaded again with
+//! `CacheMode::Offline` to continue from there. Any two snapshots or externalities can be compared
+//! the same way, e.g. before and after a migration, with a per module summary and a report that
+//! decodes simple values, also via `remote-ext snapshot compare`.
+//!
+//! ### Storage footprint
+//!
+//! To see which keys some code touches, e.g. when debugging weights or proof sizes,
+//! [`footprint::execute_recorded`] (or [`LazyExternalities::execute_recorded`]) records every read,
+//! key enumeration and net write of a closure. The resulting [`footprint::Footprint`] can be
+//! grouped by storage item, with the number of distinct and repeated reads, enumerated keys, bytes
+//! read and written, and an estimated proof size:
+//!
+//! ```ignore
+//! let (_, footprint) = footprint::execute_recorded(&mut ext, || { .. });
+//! println!("{}", footprint.report(Some(&metadata)));
+//! ```
+//!
+//! ### Following the chain
+//!
+//! A [`sidecar::Sidecar`] scrapes the state at the latest finalized block, and then follows the
+//! chain. For each new block, a hook is called with the state of the parent block, e.g. to replay
+//! the block on a pallet, the state is updated with only the storage changes of the block, and the
+//! divergence between the locally computed state and the one of the node is reported, for the
+//! scraped part of the state. The state is kept in memory, and written to the cache by
+//! `Sidecar::save`:
+//!
+//! ```ignore
+//! let hook = |block: &BlockContext| { Staking::on_initialize(block.number); };
+//! Sidecar::new(Builder::new().module("Staking"), hook)
+//!     .await?
+//!     .run(|report| assert!(report.divergence.is_empty(), "{}", report.divergence))
+//!     .await?;
+//! ```
+//!
+//! ### Managing snapshots
+//!
+//! The `remote-ext` binary manages a directory of snapshots: `remote-ext snapshot create` scrapes a
+//! new one with the same filters as the [`Builder`], `list` shows the header, size and key count of
+//! each, `inspect` counts the keys of each storage item, `compare` diffs two snapshots, `merge` and
+//! `subset` combine or narrow down snapshots, and `delete --older-than <days>` removes stale ones.
+//! The same operations are available in the [`library`] module.
 
 use std::{
-	fs,
+	borrow::Cow,
+	collections::{BTreeMap, BTreeSet, HashMap},
 	path::{Path, PathBuf},
+	sync::Arc,
 };
 use std::fmt::{Debug, Formatter, Result as FmtResult};
 use log::*;
-use sp_core::{hashing::twox_128};
+use codec::Encode;
+use futures::{stream, StreamExt};
+use sp_core::{
+	hashing::{twox_128, twox_64},
+	hexdisplay::HexDisplay,
+};
+use sub_storage::entry::{EntryKind, StorageEntry};
+use sub_storage::metadata::Metadata;
+use sub_storage::progress::{Progress, ProgressConfig};
+use sp_runtime::traits::BlakeTwo256;
 pub use sp_io::TestExternalities;
-use sp_core::storage::{StorageKey, StorageData};
-use jsonrpsee_http_client::{HttpClient, HttpConfig};
+use sp_core::storage::{StorageKey, StorageData, StorageChangeSet};
+use sp_core::offchain::{
+	testing::{TestOffchainExt, TestTransactionPoolExt},
+	OffchainExt, TransactionPoolExt,
+};
+use sp_keystore::{KeystoreExt, SyncCryptoStorePtr};
 use jsonrpsee_types::jsonrpc::{Params, to_value as to_json_value};
 
+/// The rpc client.
+pub mod client;
+/// Diffing, and persisting, the state after execution.
+pub mod diff;
+/// The errors of this crate.
+pub mod error;
+/// Executing the Wasm runtime of the chain.
+#[cfg(feature = "executor")]
+pub mod executor;
+/// Exporting a state as chain spec or json.
+pub mod export;
+/// Recording the storage accesses of executed code.
+pub mod footprint;
+/// Lazy externalities, that fetch the state on demand.
+pub mod lazy;
+/// Managing a directory of snapshots.
+pub mod library;
+/// Storage overrides files.
+pub mod overrides;
+/// Following the chain, block by block.
+pub mod sidecar;
+/// The on-disk format of the cache.
+pub mod snapshot;
+
+pub use client::{RpcClient, Transport};
+pub use error::Error;
+#[cfg(feature = "executor")]
+pub use executor::RuntimeExecutor;
+pub use lazy::{LazyBackend, LazyExternalities};
+pub use sub_storage::progress::ProgressReport;
+
+pub use snapshot::{Snapshot, SnapshotError, SnapshotHeader, SnapshotReader, SnapshotWriter};
+
 type Hash = sp_core::H256;
 type KeyPair = (StorageKey, StorageData);
+type Header = sp_runtime::generic::Header<u32, BlakeTwo256>;
 
 const LOG_TARGET: &'static str = "remote-ext";
 
+/// The environment variable that can be used to configure the cache directory.
+pub const CACHE_DIR_ENV: &'static str = "REMOTE_EXT_CACHE_DIR";
+
+/// Default number of keys requested per `state_getKeysPaged` call.
+const DEFAULT_PAGE_SIZE: u32 = 1000;
+/// Default number of values requested per `state_queryStorageAt` call.
+const DEFAULT_BATCH_SIZE: usize = 512;
+/// Default number of value requests that are in flight at the same time.
+const DEFAULT_CONCURRENCY: usize = 8;
+
+/// The name under which the block context is recorded in the filters of a snapshot, see
+/// [`Builder::block_context`].
+pub const BLOCK_CONTEXT: &'static str = "BlockContext";
+
 /// Struct for better hex printing of slice types.
 pub struct HexSlice<'a>(&'a [u8]);
 
@@ -181,15 +428,179 @@ impl<T: ?Sized + AsRef<[u8]>> HexDisplayExt for T {
 	}
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Clone, Debug)]
 /// Basic configuration for the cache behavior.
 pub enum CacheMode {
 	/// Use the cache if it is there, else create it.
+	///
+	/// A cache of another chain, block, runtime or set of filters is an error, not recreated.
 	UseElseCreate,
 	/// Force a new cache to be created from remote, then use it.
 	ForceUpdate,
 	/// None. Use remote and don't create anything.
 	None,
+	/// Use only the snapshot at the given path, and never connect to a node.
+	///
+	/// The block hash and chain name are taken from the header of the snapshot. This is useful to
+	/// make tests reproducible, e.g. in CI.
+	Offline { path: PathBuf },
+	/// Update the snapshot at the given path to the block of the builder, then use it.
+	///
+	/// Only the keys that were added, and the changes of the others, since the block of the
+	/// snapshot are downloaded. The result is saved as a new cache, tagged with the new block. A
+	/// snapshot of another chain is an error.
+	Refresh { path: PathBuf },
+}
+
+/// What to do if the state root of the built externalities does not match the one of the block.
+///
+/// The check is only possible if the entire state was scraped, i.e. no module filter is set.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum StateRootCheck {
+	/// Fail with [`Error::StateRootMismatch`] on mismatch.
+	Fail,
+	/// Only log a warning on mismatch.
+	Warn,
+	/// Don't compute the state root at all.
+	Skip,
+}
+
+/// A part of the state that should be scraped.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Filter {
+	/// All the keys of a module.
+	Module(String),
+	/// All the keys of a single storage item of a module.
+	StorageItem { module: String, item: String },
+	/// All the keys that start with the given raw prefix.
+	Prefix(Vec<u8>),
+	/// Exactly the given keys, described by `name`.
+	Keys { name: String, keys: Vec<StorageKey> },
+}
+
+impl Filter {
+	/// The prefix under which the keys of this filter must be enumerated, if any.
+	fn prefix(&self) -> Option<StorageKey> {
+		match self {
+			Self::Module(module) => Some(StorageKey(twox_128(module.as_bytes()).to_vec())),
+			Self::StorageItem { module, item } => {
+				Some(StorageKey(sub_storage::module_prefix_raw(module.as_bytes(), item.as_bytes())))
+			}
+			Self::Prefix(prefix) => Some(StorageKey(prefix.clone())),
+			Self::Keys { .. } => None,
+		}
+	}
+
+	/// True if `key` is part of the state described by this filter.
+	pub fn matches(&self, key: &StorageKey) -> bool {
+		match self {
+			Self::Keys { keys, .. } => keys.contains(key),
+			_ => self.prefix().map_or(false, |p| key.0.starts_with(&p.0)),
+		}
+	}
+
+	/// A short, stable, description of this filter, as used in cache names and headers.
+	pub fn describe(&self) -> String {
+		match self {
+			Self::Module(module) => module.clone(),
+			Self::StorageItem { module, item } => format!("{}::{}", module, item),
+			Self::Prefix(prefix) => format!("0x{}", HexDisplay::from(prefix)),
+			Self::Keys { name, keys } => {
+				let all = keys.iter().flat_map(|k| k.0.iter().cloned()).collect::<Vec<_>>();
+				format!("{}[{}]", name, HexDisplay::from(&twox_64(&all)))
+			}
+		}
+	}
+}
+
+/// Collect the keys of all the pages returned by `fetch_page`, given the last key of the previous
+/// page, until a page is empty or shorter than `page_size`.
+async fn collect_pages<F, Fut>(page_size: u32, mut fetch_page: F) -> Result<Vec<StorageKey>, Error>
+where
+	F: FnMut(Option<StorageKey>) -> Fut,
+	Fut: std::future::Future<Output = Result<Vec<StorageKey>, Error>>,
+{
+	let mut keys: Vec<StorageKey> = vec![];
+	loop {
+		let page = fetch_page(keys.last().cloned()).await?;
+		let page_len = page.len();
+		keys.extend(page);
+		debug!(target: LOG_TARGET, "fetched {} keys so far", keys.len());
+		if page_len == 0 || page_len < page_size as usize {
+			break Ok(keys);
+		}
+	}
+}
+
+/// How the keys of a state changed from one block to another.
+#[derive(Debug, Default, Eq, PartialEq)]
+struct KeyDiff {
+	/// Keys that only exist at the newer block.
+	added: Vec<StorageKey>,
+	/// Keys that exist at both blocks, whose values might have changed.
+	common: Vec<StorageKey>,
+	/// The number of keys that do not exist anymore.
+	removed: usize,
+}
+
+/// Keep the `pairs` whose key is still among `keys`, the keys at the newer block, and sort the
+/// keys into a [`KeyDiff`].
+fn diff_keys(
+	pairs: impl Iterator<Item = Result<KeyPair, Error>>,
+	keys: Vec<StorageKey>,
+) -> Result<(BTreeMap<StorageKey, StorageData>, KeyDiff), Error> {
+	let mut added = keys.into_iter().collect::<BTreeSet<_>>();
+	let mut state = BTreeMap::new();
+	let mut removed = 0;
+	for pair in pairs {
+		let (key, value) = pair?;
+		if added.remove(&key) {
+			state.insert(key, value);
+		} else {
+			removed += 1;
+		}
+	}
+	let common = state.keys().cloned().collect();
+	Ok((state, KeyDiff { added: added.into_iter().collect(), common, removed }))
+}
+
+/// Apply the `change_sets` of a `state_queryStorage` request from block `from` to `state`, and
+/// return the number of changed keys.
+///
+/// The change set of `from` itself holds the values that `state` already has, and is skipped.
+fn apply_changes(
+	state: &mut BTreeMap<StorageKey, StorageData>,
+	from: Hash,
+	change_sets: Vec<StorageChangeSet<Hash>>,
+) -> usize {
+	let mut changed = BTreeSet::new();
+	for set in change_sets.into_iter().filter(|set| set.block != from) {
+		for (key, maybe_value) in set.changes {
+			match maybe_value {
+				Some(value) => state.insert(key.clone(), value),
+				None => state.remove(&key),
+			};
+			changed.insert(key);
+		}
+	}
+	changed.len()
+}
+
+/// Anything that extensions can be registered in.
+trait RegisterExtension {
+	fn register<E: std::any::Any + sp_externalities::Extension>(&mut self, ext: E);
+}
+
+impl RegisterExtension for TestExternalities {
+	fn register<E: std::any::Any + sp_externalities::Extension>(&mut self, ext: E) {
+		self.register_extension(ext)
+	}
+}
+
+impl RegisterExtension for LazyExternalities {
+	fn register<E: std::any::Any + sp_externalities::Extension>(&mut self, ext: E) {
+		self.register_extension(ext)
+	}
 }
 
 /// The name of the cache file configuration.
@@ -200,69 +611,181 @@ pub enum CacheName {
 	Forced(String),
 }
 
+/// The part of the runtime version that we care about.
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RuntimeVersion {
+	spec_name: String,
+	spec_version: u32,
+}
+
 /// Builder for remote-externalities.
 pub struct Builder {
 	at: Option<Hash>,
 	uri: String,
 	inject: Vec<KeyPair>,
-	module_filter: Vec<String>,
+	remove: Vec<Vec<u8>>,
+	overrides: Vec<PathBuf>,
+	filters: Vec<Filter>,
+	exclude: Vec<Vec<u8>>,
 	cache_config: CacheMode,
 	cache_name_config: CacheName,
-	client: Option<HttpClient>,
+	cache_dir: Option<PathBuf>,
+	client: Option<RpcClient>,
 	chain: String,
+	header: Option<Header>,
+	spec_version: u32,
+	state_root: Option<Hash>,
+	state_root_check: StateRootCheck,
+	page_size: u32,
+	batch_size: usize,
+	concurrency: usize,
+	block_context: bool,
+	context: Vec<KeyPair>,
+	offchain: Option<TestOffchainExt>,
+	transaction_pool: Option<TestTransactionPoolExt>,
+	keystore: Option<SyncCryptoStorePtr>,
+	progress: ProgressConfig,
+	modules: HashMap<[u8; 16], String>,
 }
 
 impl Default for Builder {
 	fn default() -> Self {
 		Self {
-			uri: "http://localhost:9933".into(),
+			uri: "ws://localhost:9944".into(),
 			at: Default::default(),
 			inject: Default::default(),
-			module_filter: Default::default(),
+			remove: Default::default(),
+			overrides: Default::default(),
+			filters: Default::default(),
+			exclude: Default::default(),
 			cache_config: CacheMode::None,
 			cache_name_config: CacheName::Auto,
+			cache_dir: None,
 			client: None,
 			chain: "UNSET".into(),
+			header: None,
+			spec_version: 0,
+			state_root: None,
+			state_root_check: StateRootCheck::Fail,
+			page_size: DEFAULT_PAGE_SIZE,
+			batch_size: DEFAULT_BATCH_SIZE,
+			concurrency: DEFAULT_CONCURRENCY,
+			block_context: false,
+			context: Default::default(),
+			offchain: None,
+			transaction_pool: None,
+			keystore: None,
+			progress: ProgressConfig::new(LOG_TARGET),
+			modules: Default::default(),
 		}
 	}
 }
 
 // RPC methods
 impl Builder {
-	async fn rpc_get_head(&self) -> Hash {
-		let json_value = self
-			.rpc_client()
-			.request("chain_getFinalizedHead", Params::None)
+	async fn rpc_get_head(&self) -> Result<Hash, Error> {
+		self.rpc_client().request("chain_getFinalizedHead", Params::None).await
+	}
+
+	/// Relay the request to `state_getKeysPaged` rpc endpoint.
+	async fn rpc_get_keys_paged(
+		&self,
+		prefix: &StorageKey,
+		start_key: Option<&StorageKey>,
+		at: Hash,
+	) -> Result<Vec<StorageKey>, Error> {
+		let serialized_prefix = to_json_value(prefix).expect("StorageKey serialization infallible");
+		let count = to_json_value(self.page_size.max(1)).expect("u32 serialization infallible");
+		let start_key = to_json_value(start_key).expect("StorageKey serialization infallible");
+		let at = to_json_value(at).expect("Block hash serialization infallible");
+		self.rpc_client()
+			.request(
+				"state_getKeysPaged",
+				Params::Array(vec![serialized_prefix, count, start_key, at]),
+			)
 			.await
-			.expect("get chain finalized head request failed");
-		jsonrpsee_types::jsonrpc::from_value(json_value).unwrap()
 	}
 
-	/// Relay the request to `state_getPairs` rpc endpoint.
+	/// Relay the request to `state_queryStorageAt` rpc endpoint.
 	///
-	/// Note that this is an unsafe RPC.
-	async fn rpc_get_pairs(&self, prefix: StorageKey, at: Hash) -> Vec<KeyPair> {
-		let serialized_prefix = to_json_value(prefix).expect("StorageKey serialization infallible");
+	/// Keys that do not exist are not returned.
+	async fn rpc_query_storage_at(
+		&self,
+		keys: Vec<StorageKey>,
+		at: Hash,
+	) -> Result<Vec<KeyPair>, Error> {
+		let serialized_keys = to_json_value(keys).expect("StorageKey serialization infallible");
 		let at = to_json_value(at).expect("Block hash serialization infallible");
-		let json_value = self
+		let change_sets: Vec<StorageChangeSet<Hash>> = self
 			.rpc_client()
-			.request("state_getPairs", Params::Array(vec![serialized_prefix, at]))
-			.await
-			.expect("Storage state_getPairs failed");
-		jsonrpsee_types::jsonrpc::from_value(json_value).unwrap()
+			.request("state_queryStorageAt", Params::Array(vec![serialized_keys, at]))
+			.await?;
+		Ok(change_sets
+			.into_iter()
+			.flat_map(|set| set.changes)
+			.filter_map(|(k, maybe_v)| maybe_v.map(|v| (k, v)))
+			.collect())
 	}
 
-	/// Get the chain name.
-	async fn chain_name(&self) -> String {
-		let json_value = self
+	/// Relay the request to `state_queryStorage` rpc endpoint.
+	///
+	/// The first change set holds the values of `keys` at `from`, the following ones their changes
+	/// in each block up to `to`.
+	async fn rpc_query_storage(
+		&self,
+		keys: Vec<StorageKey>,
+		from: Hash,
+		to: Hash,
+	) -> Result<Vec<StorageChangeSet<Hash>>, Error> {
+		let keys = to_json_value(keys).expect("StorageKey serialization infallible");
+		let from = to_json_value(from).expect("Block hash serialization infallible");
+		let to = to_json_value(to).expect("Block hash serialization infallible");
+		self.rpc_client().request("state_queryStorage", Params::Array(vec![keys, from, to])).await
+	}
+
+	/// Get the header of the given block.
+	async fn rpc_get_header(&self, at: Hash) -> Result<Header, Error> {
+		let serialized_at = to_json_value(at).expect("Block hash serialization infallible");
+		let maybe_header: Option<Header> = self
 			.rpc_client()
-			.request("system_chain", Params::None)
-			.await
-			.expect("system_chain failed");
-		jsonrpsee_types::jsonrpc::from_value(json_value).unwrap()
+			.request("chain_getHeader", Params::Array(vec![serialized_at]))
+			.await?;
+		maybe_header.ok_or(Error::BlockNotFound(at))
+	}
+
+	/// Get the spec version of the runtime at the given block.
+	async fn rpc_get_spec_version(&self, at: Hash) -> Result<u32, Error> {
+		Ok(self.rpc_get_runtime_version(at).await?.spec_version)
+	}
+
+	/// Get the version of the runtime at the given block.
+	async fn rpc_get_runtime_version(&self, at: Hash) -> Result<RuntimeVersion, Error> {
+		let at = to_json_value(at).expect("Block hash serialization infallible");
+		self.rpc_client().request("state_getRuntimeVersion", Params::Array(vec![at])).await
 	}
 
-	fn rpc_client(&self) -> &HttpClient {
+	/// Get the metadata of the runtime at the given block.
+	async fn rpc_get_metadata(&self, at: Hash) -> Result<Metadata, Error> {
+		let version = self.rpc_get_runtime_version(at).await?;
+		let at = to_json_value(at).expect("Block hash serialization infallible");
+		let raw: sp_core::Bytes =
+			self.rpc_client().request("state_getMetadata", Params::Array(vec![at])).await?;
+		Ok(Metadata::decode(version.spec_name, version.spec_version, raw.0))
+	}
+
+	/// Get the hash of the block with the given number, if it exists.
+	async fn rpc_get_block_hash(&self, number: u32) -> Result<Option<Hash>, Error> {
+		let number = to_json_value(number).expect("Block number serialization infallible");
+		self.rpc_client().request("chain_getBlockHash", Params::Array(vec![number])).await
+	}
+
+	/// Get the chain name.
+	async fn chain_name(&self) -> Result<String, Error> {
+		self.rpc_client().request("system_chain", Params::None).await
+	}
+
+	fn rpc_client(&self) -> &RpcClient {
 		self.client.as_ref().expect("Client initialized after `build`; qed")
 	}
 }
@@ -273,43 +796,174 @@ impl Builder {
 	fn final_cache_name(&self) -> String {
 		match &self.cache_name_config {
 			CacheName::Auto => {
-				format!("{},{:?},{}.bin", self.chain, self.final_at(), self.module_filter.join(","))
+				format!(
+					"{},{:?},{}.bin",
+					self.chain,
+					self.final_at(),
+					self.filter_names().join(",")
+				)
 			}
 			CacheName::Forced(name) => name.clone(),
 		}
 	}
 
-	/// Directory at which to create the cache. Not configurable for now.
-	// TODO
-	fn cache_dir() -> &'static str {
-		"."
+	/// The description of all the filters and exclusions, as stored in the snapshot header.
+	fn filter_names(&self) -> Vec<String> {
+		self.filters
+			.iter()
+			.map(|f| f.describe())
+			.chain(self.exclude.iter().map(|e| format!("!0x{}", HexDisplay::from(e))))
+			.chain(self.stores_block_context().then(|| BLOCK_CONTEXT.to_string()))
+			.collect()
+	}
+
+	/// True if the block context is added to the scraped state, see [`Builder::block_context`].
+	///
+	/// A full scrape holds the block context anyway, as written by the runtime.
+	fn stores_block_context(&self) -> bool {
+		self.block_context && !self.is_full_scrape()
+	}
+
+	/// True if the entire state of the chain is being scraped.
+	fn is_full_scrape(&self) -> bool {
+		self.filters.is_empty() && self.exclude.is_empty()
+	}
+
+	/// True if `key` should not be scraped, as per [`Builder::exclude`].
+	fn is_excluded(&self, key: &StorageKey) -> bool {
+		self.exclude.iter().any(|e| key.0.starts_with(e))
+	}
+
+	/// True if `key` is part of the scraped state, as per the filters and exclusions.
+	fn is_tracked(&self, key: &StorageKey) -> bool {
+		let filtered = self.filters.is_empty() || self.filters.iter().any(|f| f.matches(key));
+		filtered && !self.is_excluded(key)
+	}
+
+	/// Directory at which to create the cache.
+	///
+	/// This is, in order of priority, the one set by [`Builder::cache_dir`], the one given by the
+	/// [`CACHE_DIR_ENV`] environment variable, or the current directory.
+	fn final_cache_dir(&self) -> PathBuf {
+		self.cache_dir
+			.clone()
+			.or_else(|| std::env::var_os(CACHE_DIR_ENV).map(PathBuf::from))
+			.unwrap_or_else(|| PathBuf::from("."))
 	}
 
 	/// The final path of the cache.
 	fn cache_path(&self) -> PathBuf {
-		Path::new(Self::cache_dir()).join(self.final_cache_name())
+		self.final_cache_dir().join(self.final_cache_name())
+	}
+
+	/// The header of the snapshot that would be created from this builder.
+	fn snapshot_header(&self) -> SnapshotHeader {
+		let header = self.header.as_ref().expect("Header initialized after `build`; qed");
+		SnapshotHeader {
+			chain: self.chain.clone(),
+			block_hash: self.final_at(),
+			block_number: header.number,
+			spec_version: self.spec_version,
+			state_root: header.state_root,
+			filter: self.filter_names(),
+			created_at: SnapshotHeader::now(),
+		}
 	}
 
 	/// Save the given data as cache.
-	fn save_cache(&self, data: &[KeyPair]) {
-		let bdata = bincode::serialize(data).unwrap();
+	fn save_cache(&self, data: &[KeyPair]) -> Result<(), Error> {
 		let path = self.cache_path();
 		info!(target: LOG_TARGET, "writing to cache file {:?}", path);
-		fs::write(path, bdata).unwrap();
+		Snapshot::write(&path, &self.snapshot_header(), data)
+			.map_err(|error| Error::Cache { path, error })
+	}
+
+	/// Fail if the snapshot of `header` is not of the chain, block, runtime and filters of `self`.
+	fn check_cache_header(&self, header: &SnapshotHeader) -> Result<(), SnapshotError> {
+		let filter = self.filter_names();
+		let expected = (&self.chain, self.final_at(), self.spec_version, &filter);
+		if (&header.chain, header.block_hash, header.spec_version, &header.filter) == expected {
+			return Ok(());
+		}
+		Err(SnapshotError::Incompatible(format!(
+			"cache is of {} at {:?} (spec version {}) with filters {:?}, expected {} at {:?} \
+			 (spec version {}) with filters {:?}",
+			header.chain,
+			header.block_hash,
+			header.spec_version,
+			header.filter,
+			self.chain,
+			self.final_at(),
+			self.spec_version,
+			filter,
+		)))
 	}
 
-	/// Try and initialize `Self` from cache
-	fn try_scrape_cached(&self) -> Result<Vec<KeyPair>, &'static str> {
+	/// Try and fill `ext` from cache, streaming the pairs directly into it.
+	///
+	/// Fails with [`SnapshotError::Incompatible`] if the cache is not of the block, runtime and
+	/// filters of `self`, e.g. if it was copied into the cache directory.
+	fn try_load_cached(&self, ext: &mut TestExternalities) -> Result<usize, SnapshotError> {
 		info!(
 			target: LOG_TARGET,
 			"scraping keypairs from cache {:?} @ {:?}",
 			self.cache_path(),
 			self.final_at()
 		);
-		let path = self.cache_path();
-		fs::read(path)
-			.map_err(|_| "failed to read cache")
-			.and_then(|b| bincode::deserialize(&b[..]).map_err(|_| "failed to decode cache"))
+		let reader = SnapshotReader::open(&self.cache_path())?;
+		let header = reader.header();
+		info!(
+			target: LOG_TARGET,
+			"loading cache of {} at #{} ({:?}, spec version {}), created at {}",
+			header.chain,
+			header.block_number,
+			header.block_hash,
+			header.spec_version,
+			header.created_at,
+		);
+		self.check_cache_header(header)?;
+		reader.load_into(ext)
+	}
+
+	/// Read all the overrides files into injections and removals.
+	fn load_overrides(&mut self) -> Result<(), Error> {
+		for path in std::mem::take(&mut self.overrides) {
+			for o in overrides::load(&path)? {
+				let key = o.key().expect("keys are checked when loading; qed");
+				match o.value {
+					Some(value) => self.inject.push((StorageKey(key), StorageData(value.0))),
+					None => self.remove.push(key),
+				}
+			}
+		}
+		Ok(())
+	}
+
+	/// Apply the removals, then the injections. Must be called within externalities.
+	fn apply_overrides(&self) {
+		info!(
+			target: LOG_TARGET,
+			"removing {} prefixes, injecting a total of {} keys",
+			self.remove.len(),
+			self.inject.len()
+		);
+		for prefix in &self.remove {
+			sp_io::storage::clear_prefix(prefix);
+		}
+		for (k, v) in &self.inject {
+			trace!(target: LOG_TARGET, "injecting {:?} -> {:?}", k.hex_display(), v.hex_display());
+			sp_io::storage::set(&k.0, &v.0);
+		}
+	}
+
+	/// Insert all the given pairs into `ext`.
+	fn insert_pairs(ext: &mut TestExternalities, pairs: Vec<KeyPair>) {
+		info!(target: LOG_TARGET, "injecting a total of {} keys", pairs.len());
+		for (k, v) in pairs {
+			let (k, v) = (k.0, v.0);
+			trace!(target: LOG_TARGET, "injecting {:?} -> {:?}", k.hex_display(), v.hex_display());
+			ext.insert(k, v);
+		}
 	}
 
 	/// Get the final `at` that shall be used.
@@ -319,106 +973,565 @@ impl Builder {
 		self.at.expect("At intialized after `built`; qed")
 	}
 
-	/// Build `Self` from a network node denoted by `uri`.
-	async fn scrape_remote(&self) -> Vec<KeyPair> {
-		let at = self.final_at();
-		info!(target: LOG_TARGET, "scraping keypairs from remote node {} @ {:?}", self.uri, at);
+	/// Get all the keys located under `prefix`, one page at a time.
+	async fn get_keys(&self, prefix: &StorageKey, at: Hash) -> Result<Vec<StorageKey>, Error> {
+		debug!(target: LOG_TARGET, "fetching keys under {:?}", prefix);
+		collect_pages(self.page_size.max(1), |start_key| async move {
+			self.rpc_get_keys_paged(prefix, start_key.as_ref(), at).await
+		})
+		.await
+	}
+
+	/// The module that `key` is reported under in the progress breakdown.
+	///
+	/// This is the module of the key as per the metadata, if given and the key is known, else the
+	/// description of the first filter that matches the key, or else the hex encoded hash of its
+	/// module.
+	fn progress_module_of(&self, key: &StorageKey) -> Cow<'_, str> {
+		if let Some(module) = key.0.get(..16).and_then(|prefix| self.modules.get(prefix)) {
+			module.as_str().into()
+		} else if let Some(filter) = self.filters.iter().find(|f| f.matches(key)) {
+			filter.describe().into()
+		} else if key.0.starts_with(b":") {
+			"well-known".into()
+		} else {
+			format!("0x{}", HexDisplay::from(&&key.0[..key.0.len().min(16)])).into()
+		}
+	}
+
+	/// Fetch the values of all the given keys, in parallel batches.
+	async fn get_values(&self, keys: Vec<StorageKey>, at: Hash) -> Result<Vec<KeyPair>, Error> {
+		let mut progress =
+			Progress::new(self.progress.clone(), "downloading values", "keys", Some(keys.len()));
+		let mut key_values = Vec::with_capacity(keys.len());
+
+		let batches = keys.chunks(self.batch_size.max(1)).map(|c| c.to_vec()).collect::<Vec<_>>();
+		let mut values = stream::iter(batches)
+			.map(|batch| self.rpc_query_storage_at(batch, at))
+			.buffer_unordered(self.concurrency.max(1));
+
+		while let Some(batch) = values.next().await {
+			let batch = batch?;
+			for (k, v) in batch.iter() {
+				progress.record(&self.progress_module_of(k), 1, k.0.len() + v.0.len());
+			}
+			key_values.extend(batch);
+		}
+
+		progress.finish();
+		Ok(key_values)
+	}
 
-		let mut keys_and_values = if self.module_filter.len() > 0 {
-			let mut filtered_kv = vec![];
-			for f in self.module_filter.iter() {
-				let hashed_prefix = StorageKey(twox_128(f.as_bytes()).to_vec());
-				let module_kv = self.rpc_get_pairs(hashed_prefix.clone(), at).await;
+	/// Enumerate all the keys that should be scraped at `at`, as per the filters.
+	async fn enumerate_keys(&self, at: Hash) -> Result<Vec<StorageKey>, Error> {
+		let mut keys = vec![];
+		if self.filters.is_empty() {
+			info!(target: LOG_TARGET, "enumerating keys of all modules.");
+			keys = self.get_keys(&StorageKey(vec![]), at).await?;
+		} else {
+			for filter in self.filters.iter() {
+				let filter_keys = match filter.prefix() {
+					Some(prefix) => self.get_keys(&prefix, at).await?,
+					None => match filter {
+						Filter::Keys { keys, .. } => keys.clone(),
+						_ => unreachable!("only `Filter::Keys` has no prefix; qed"),
+					},
+				};
 				info!(
 					target: LOG_TARGET,
-					"downloaded data for module {} (count: {} / prefix: {:?}).",
-					f,
-					module_kv.len(),
-					hashed_prefix,
+					"enumerated keys of {} (count: {}).",
+					filter.describe(),
+					filter_keys.len(),
 				);
-				filtered_kv.extend(module_kv);
+				keys.extend(filter_keys);
 			}
-			filtered_kv
-		} else {
-			info!(target: LOG_TARGET, "downloading data for all modules.");
-			self.rpc_get_pairs(StorageKey(vec![]), at).await.into_iter().collect::<Vec<_>>()
-		};
+			// filters might overlap.
+			keys.sort();
+			keys.dedup();
+		}
 
-		// concat any custom key values.
-		keys_and_values.extend(self.inject.clone());
-		keys_and_values
+		let total = keys.len();
+		keys.retain(|k| !self.is_excluded(k));
+		if keys.len() < total {
+			info!(target: LOG_TARGET, "excluded {} keys.", total - keys.len());
+		}
+		Ok(keys)
 	}
 
-	async fn force_update(&self) -> Vec<KeyPair> {
-		let kp = self.scrape_remote().await;
-		self.save_cache(&kp);
-		kp
+	/// Build `Self` from a network node denoted by `uri`.
+	///
+	/// Keys are first enumerated using `state_getKeysPaged`, then their values are fetched in
+	/// batches of `batch_size`, with at most `concurrency` batches in flight.
+	async fn scrape_remote(&self) -> Result<Vec<KeyPair>, Error> {
+		let at = self.final_at();
+		info!(target: LOG_TARGET, "scraping keypairs from remote node {} @ {:?}", self.uri, at);
+		let keys = self.enumerate_keys(at).await?;
+		Ok(self.add_block_context(self.get_values(keys, at).await?))
 	}
 
-	async fn pre_build(mut self) -> Vec<KeyPair> {
-		self.client = Some(
-			HttpClient::new(
-				self.uri.clone(),
-				HttpConfig { max_request_body_size: u32::max_value() },
-			)
-			.unwrap(),
+	/// Update the pairs of the snapshot at `path` to the block of `self`.
+	///
+	/// The snapshot is streamed, and must be of the same chain as the node.
+	async fn refresh_remote(&self, path: &Path) -> Result<Vec<KeyPair>, Error> {
+		let cache_error = |error: SnapshotError| Error::Cache { path: path.to_path_buf(), error };
+		let reader = SnapshotReader::open(path).map_err(cache_error)?;
+		let old = reader.header().clone();
+		info!(
+			target: LOG_TARGET,
+			"refreshing {:?} from #{} ({:?}) to {:?}",
+			path,
+			old.block_number,
+			old.block_hash,
+			self.final_at(),
 		);
-		self.at = match self.at {
-			Some(at) => Some(at),
-			None => Some(self.rpc_get_head().await),
-		};
-		self.chain = self.chain_name().await;
+		if old.chain != self.chain {
+			return Err(cache_error(SnapshotError::Incompatible(format!(
+				"snapshot is of chain {}, but the node of {}",
+				old.chain, self.chain,
+			))));
+		}
+		if old.filter != self.filter_names() {
+			warn!(
+				target: LOG_TARGET,
+				"snapshot filters {:?} differ from the ones of the builder {:?}, which are used",
+				old.filter,
+				self.filter_names(),
+			);
+		}
 
-		match self.cache_config {
-			CacheMode::None => self.scrape_remote().await,
-			CacheMode::ForceUpdate => self.force_update().await,
-			CacheMode::UseElseCreate => match self.try_scrape_cached() {
-				Ok(kp) => kp,
-				Err(why) => {
-					warn!(target: LOG_TARGET, "failed to load cache due to {:?}", why);
-					self.force_update().await
-				}
-			},
+		let pairs = reader.map(|pair| pair.map_err(cache_error));
+		let pairs = self.update_pairs(pairs, old.block_hash).await?;
+		Ok(self.add_block_context(pairs.into_iter().collect()))
+	}
+
+	/// Update `pairs`, the state at block `from`, to the block of `self`.
+	///
+	/// The keys are enumerated again, to find the added and removed ones. Added keys are fetched,
+	/// and the remaining ones only updated with the changes that `state_queryStorage` reports from
+	/// `from` on, in batches of `batch_size`, with at most `concurrency` batches in flight.
+	async fn update_pairs(
+		&self,
+		pairs: impl Iterator<Item = Result<KeyPair, Error>>,
+		from: Hash,
+	) -> Result<BTreeMap<StorageKey, StorageData>, Error> {
+		let at = self.final_at();
+		let (mut state, diff) = diff_keys(pairs, self.enumerate_keys(at).await?)?;
+
+		let batches = diff
+			.common
+			.chunks(self.batch_size.max(1))
+			.map(|batch| batch.to_vec())
+			.collect::<Vec<_>>();
+		let mut changed = 0;
+		let mut queries = stream::iter(batches)
+			.map(|batch| self.rpc_query_storage(batch, from, at))
+			.buffer_unordered(self.concurrency.max(1));
+		while let Some(change_sets) = queries.next().await {
+			changed += apply_changes(&mut state, from, change_sets?);
 		}
+
+		info!(
+			target: LOG_TARGET,
+			"update from {:?}: {} keys changed, {} removed, fetching {} new keys",
+			from,
+			changed,
+			diff.removed,
+			diff.added.len(),
+		);
+		state.extend(self.get_values(diff.added, at).await?);
+		Ok(state)
 	}
-}
 
-// Public methods
-impl Builder {
-	/// Create a new builder.
-	pub fn new() -> Self {
-		Default::default()
+	async fn force_update(&self, ext: &mut TestExternalities) -> Result<(), Error> {
+		let kp = self.scrape_remote().await?;
+		self.save_cache(&kp)?;
+		Self::insert_pairs(ext, kp);
+		Ok(())
 	}
 
-	/// Scrape the chain at the given block hash.
-	///
-	/// If not set, latest finalized will be used.
-	pub fn at(mut self, at: Hash) -> Self {
-		self.at = Some(at);
-		self
+	/// Fill `ext` purely from the snapshot at `path`.
+	fn load_offline(&mut self, path: &Path, ext: &mut TestExternalities) -> Result<(), Error> {
+		info!(target: LOG_TARGET, "loading keypairs offline from {:?}", path);
+		let cache_error = |error| Error::Cache { path: path.to_path_buf(), error };
+		let reader = SnapshotReader::open(path).map_err(cache_error)?;
+		let header = reader.header().clone();
+		if let Some(at) = self.at {
+			if at != header.block_hash {
+				warn!(
+					target: LOG_TARGET,
+					"requested block {:?}, but snapshot is at {:?}. Using the snapshot.",
+					at,
+					header.block_hash,
+				);
+			}
+		}
+		self.at = Some(header.block_hash);
+		self.chain = header.chain;
+		self.state_root = if header.filter.is_empty() { Some(header.state_root) } else { None };
+		let has_context =
+			header.filter.is_empty() || header.filter.iter().any(|f| f == BLOCK_CONTEXT);
+		let count = reader.load_into(ext).map_err(cache_error)?;
+		info!(target: LOG_TARGET, "loaded {} keys from {:?}", count, path);
+		if self.block_context && !has_context {
+			warn!(
+				target: LOG_TARGET,
+				"{:?} was not created with the block context, only `System::Number` is set",
+				path,
+			);
+			let number = sub_storage::module_prefix_raw(b"System", b"Number");
+			ext.insert(number, header.block_number.encode());
+		}
+		Ok(())
 	}
 
-	/// Look for a chain at the given URI.
+	/// The keys of the block context, i.e. `System::Number`, `System::ParentHash`, `System::Digest`
+	/// and `Timestamp::Now`, and their values at the block of `header`.
 	///
-	/// If not set, `ws://localhost:9944` will be used.
-	pub fn uri(mut self, uri: String) -> Self {
-		self.uri = uri;
-		self
+	/// The first three are encoded from `header`, the timestamp is the given value of
+	/// `Timestamp::Now`, if any.
+	fn block_context_pairs(header: &Header, timestamp: Option<StorageData>) -> Vec<KeyPair> {
+		let key =
+			|module: &[u8], item: &[u8]| StorageKey(sub_storage::module_prefix_raw(module, item));
+		let mut pairs = vec![
+			(key(b"System", b"Number"), StorageData(header.number.encode())),
+			(key(b"System", b"ParentHash"), StorageData(header.parent_hash.encode())),
+			(key(b"System", b"Digest"), StorageData(header.digest.encode())),
+		];
+		pairs.extend(timestamp.map(|now| (key(b"Timestamp", b"Now"), now)));
+		pairs
 	}
 
-	/// Inject a manual list of key and values to the storage.
-	pub fn inject(mut self, injections: &[KeyPair]) -> Self {
-		for i in injections {
-			self.inject.push(i.clone());
+	/// Fetch the block context of the block being scraped, see [`Builder::block_context_pairs`].
+	async fn fetch_block_context(&self) -> Result<Vec<KeyPair>, Error> {
+		let header = self.header.as_ref().expect("Header initialized after `build`; qed");
+		let now = StorageKey(sub_storage::module_prefix_raw(b"Timestamp", b"Now"));
+		let timestamp = self.rpc_query_storage_at(vec![now], self.final_at()).await?.pop();
+		if timestamp.is_none() {
+			warn!(target: LOG_TARGET, "`Timestamp::Now` not found, the block context has none");
 		}
-		self
+		Ok(Self::block_context_pairs(header, timestamp.map(|(_, now)| now)))
 	}
 
-	/// Scrape only this module.
+	/// Add the block context to the scraped `pairs`, if it must be stored.
+	///
+	/// The block context takes precedence over scraped values of the same keys.
+	fn add_block_context(&self, mut pairs: Vec<KeyPair>) -> Vec<KeyPair> {
+		if self.stores_block_context() {
+			pairs.retain(|(k, _)| self.context.iter().all(|(c, _)| c != k));
+			pairs.extend(self.context.iter().cloned());
+		}
+		pairs
+	}
+
+	/// Register the extensions given to the builder in `ext`.
+	fn register_extensions<X: RegisterExtension>(&self, ext: &mut X) {
+		if let Some(offchain) = self.offchain.clone() {
+			ext.register(OffchainExt::new(offchain));
+		}
+		if let Some(pool) = self.transaction_pool.clone() {
+			ext.register(TransactionPoolExt::new(pool));
+		}
+		if let Some(keystore) = self.keystore.clone() {
+			ext.register(KeystoreExt(keystore));
+		}
+	}
+
+	/// Connect to the remote node, and settle on the block to scrape, the latest finalized one if
+	/// none is set.
+	async fn connect(&mut self) -> Result<(), Error> {
+		if self.client.is_none() {
+			self.client = Some(RpcClient::new(&self.uri).await?);
+		}
+		self.at = match self.at {
+			Some(at) => Some(at),
+			None => Some(self.rpc_get_head().await?),
+		};
+		Ok(())
+	}
+
+	/// Connect to the remote node, and fetch everything about the block that is being scraped.
+	async fn init_remote(&mut self) -> Result<(), Error> {
+		self.connect().await?;
+		self.chain = self.chain_name().await?;
+		let header = self.rpc_get_header(self.final_at()).await?;
+		self.state_root = if self.is_full_scrape() { Some(header.state_root) } else { None };
+		self.header = Some(header);
+		self.spec_version = self.rpc_get_spec_version(self.final_at()).await?;
+		if self.block_context {
+			self.context = self.fetch_block_context().await?;
+		}
+		Ok(())
+	}
+
+	async fn pre_build(&mut self, ext: &mut TestExternalities) -> Result<(), Error> {
+		if let CacheMode::Offline { path } = self.cache_config.clone() {
+			return self.load_offline(&path, ext);
+		}
+
+		self.init_remote().await?;
+		match self.cache_config {
+			CacheMode::None => {
+				let kp = self.scrape_remote().await?;
+				Self::insert_pairs(ext, kp);
+			}
+			CacheMode::ForceUpdate => self.force_update(ext).await?,
+			CacheMode::UseElseCreate => match self.try_load_cached(ext) {
+				Ok(count) => info!(target: LOG_TARGET, "loaded {} keys from cache", count),
+				Err(error @ SnapshotError::Incompatible(_)) => {
+					return Err(Error::Cache { path: self.cache_path(), error })
+				}
+				Err(why) => {
+					warn!(target: LOG_TARGET, "failed to load cache due to: {}", why);
+					// the cache might have been partially loaded.
+					*ext = TestExternalities::new_empty();
+					self.force_update(ext).await?
+				}
+			},
+			CacheMode::Refresh { ref path } => {
+				let kp = self.refresh_remote(path).await?;
+				self.save_cache(&kp)?;
+				Self::insert_pairs(ext, kp);
+			}
+			CacheMode::Offline { .. } => unreachable!("offline mode handled above; qed"),
+		}
+		Ok(())
+	}
+
+	/// Compare the state root of `ext` with the one of the scraped block, if it is known.
+	fn check_state_root(&self, ext: &mut TestExternalities) -> Result<(), Error> {
+		let expected = match (self.state_root, self.state_root_check) {
+			(Some(expected), check) if check != StateRootCheck::Skip => expected,
+			_ => return Ok(()),
+		};
+
+		let computed = Hash::from_slice(&ext.execute_with(|| sp_io::storage::root()));
+		if computed == expected {
+			info!(target: LOG_TARGET, "state root {:?} verified", computed);
+			return Ok(());
+		}
+
+		let error = Error::StateRootMismatch { expected, computed };
+		match self.state_root_check {
+			StateRootCheck::Fail => Err(error),
+			_ => {
+				warn!(target: LOG_TARGET, "at {:?}: {}", self.final_at(), error);
+				Ok(())
+			}
+		}
+	}
+}
+
+// Public methods
+impl Builder {
+	/// Create a new builder.
+	pub fn new() -> Self {
+		Default::default()
+	}
+
+	/// Scrape the chain at the given block hash.
+	///
+	/// If not set, latest finalized will be used.
+	pub fn at(mut self, at: Hash) -> Self {
+		self.at = Some(at);
+		self
+	}
+
+	/// Look for a chain at the given URI.
+	///
+	/// Both `ws://`/`wss://` and `http://`/`https://` URIs are supported, and the transport is
+	/// chosen based on the scheme. If not set, `ws://localhost:9944` will be used.
+	pub fn uri(mut self, uri: String) -> Self {
+		self.uri = uri;
+		self
+	}
+
+	/// Inject a manual list of key and values to the storage.
+	///
+	/// Injections are applied after scraping (or loading from cache), and are never stored in the
+	/// cache.
+	pub fn inject(mut self, injections: &[KeyPair]) -> Self {
+		for i in injections {
+			self.inject.push(i.clone());
+		}
+		self
+	}
+
+	/// Inject the given value of a plain storage item, e.g. `("Staking", "ValidatorCount")`.
+	pub fn inject_value<V: codec::Encode>(self, module: &str, item: &str, value: &V) -> Self {
+		let key = sub_storage::value_key(module.as_bytes(), item.as_bytes());
+		self.inject(&[(key, StorageData(value.encode()))])
+	}
+
+	/// Inject the value of `key` in a storage map, whose keys are hashed with `H`.
+	pub fn inject_map<H: frame_support::StorageHasher, K: codec::Encode, V: codec::Encode>(
+		self,
+		module: &str,
+		item: &str,
+		key: &K,
+		value: &V,
+	) -> Self {
+		let key = sub_storage::map_key::<H>(module.as_bytes(), item.as_bytes(), &key.encode());
+		self.inject(&[(key, StorageData(value.encode()))])
+	}
+
+	/// Remove all the keys that start with the given raw prefix, after scraping.
+	///
+	/// Removals are applied before injections, so that e.g. a map can be cleared and then filled
+	/// with injected values.
+	pub fn remove_prefix(mut self, prefix: &[u8]) -> Self {
+		self.remove.push(prefix.to_vec());
+		self
+	}
+
+	/// Apply the overrides in the json or yaml file at `path`, after scraping.
+	///
+	/// See the [`overrides`] module for the format of the file. The file is only read when
+	/// building.
+	pub fn overrides_file<P: Into<PathBuf>>(mut self, path: P) -> Self {
+		self.overrides.push(path.into());
+		self
+	}
+
+	/// Scrape only this module.
 	///
 	/// If used multiple times, all of the given modules will be used, else the entire chain.
 	pub fn module(mut self, module: &str) -> Self {
-		self.module_filter.push(module.to_string());
+		self.filters.push(Filter::Module(module.to_string()));
+		self
+	}
+
+	/// Scrape only this storage item of the given module, e.g. `("System", "Account")`.
+	///
+	/// Can be combined with all other filters.
+	pub fn storage_item(mut self, module: &str, item: &str) -> Self {
+		self.filters
+			.push(Filter::StorageItem { module: module.to_string(), item: item.to_string() });
+		self
+	}
+
+	/// Scrape only the keys that start with the given raw prefix.
+	///
+	/// Can be combined with all other filters.
+	pub fn prefix(mut self, prefix: &[u8]) -> Self {
+		self.filters.push(Filter::Prefix(prefix.to_vec()));
+		self
+	}
+
+	/// Scrape only the values of `entry` under the given `keys`, e.g. the `Staking::Ledger` of
+	/// a few accounts.
+	///
+	/// Keys that don't exist are silently ignored. Can be combined with all other filters.
+	pub fn map_keys<K: EntryKind, V: codec::Decode>(
+		mut self,
+		entry: StorageEntry<K, V>,
+		keys: &[K::Key],
+	) -> Self {
+		let name = format!("{}::{}", entry.module, entry.name);
+		let keys = keys.iter().map(|k| entry.key(k)).collect::<Vec<_>>();
+		self.filters.push(Filter::Keys { name, keys });
+		self
+	}
+
+	/// Never scrape any key that starts with the given raw prefix.
+	///
+	/// Exclusions are applied after all other filters.
+	pub fn exclude(mut self, prefix: &[u8]) -> Self {
+		self.exclude.push(prefix.to_vec());
+		self
+	}
+
+	/// Never scrape any key of the given storage item, e.g. `("System", "Events")`.
+	pub fn exclude_item(self, module: &str, item: &str) -> Self {
+		self.exclude(&sub_storage::module_prefix_raw(module.as_bytes(), item.as_bytes()))
+	}
+
+	/// The number of keys requested per `state_getKeysPaged` call. At least one key is requested.
+	pub fn page_size(mut self, page_size: u32) -> Self {
+		self.page_size = page_size;
+		self
+	}
+
+	/// The number of values requested per `state_queryStorageAt` call.
+	pub fn batch_size(mut self, batch_size: usize) -> Self {
+		self.batch_size = batch_size;
+		self
+	}
+
+	/// The maximum number of `state_queryStorageAt` calls in flight at the same time.
+	pub fn concurrency(mut self, concurrency: usize) -> Self {
+		self.concurrency = concurrency;
+		self
+	}
+
+	/// Make sure the externalities hold the context of the scraped block, i.e. `System::Number`,
+	/// `System::ParentHash`, `System::Digest` and `Timestamp::Now`.
+	///
+	/// Without this, code that is executed in externalities of a filtered state sees block number 0
+	/// and no timestamp. The number, parent hash and digest are encoded from the block header, and
+	/// the timestamp is fetched from the node. They are added to the scraped state, and thus saved
+	/// in the cache, so that offline builds from it have them as well. Lazy externalities start
+	/// with them. A full scrape holds them anyway, and is left untouched.
+	pub fn block_context(mut self) -> Self {
+		self.block_context = true;
+		self
+	}
+
+	/// Register the given offchain extension in the built externalities.
+	///
+	/// Its state, e.g. the random seed, can be set via the `OffchainState` returned by
+	/// `TestOffchainExt::new`.
+	pub fn offchain(mut self, offchain: TestOffchainExt) -> Self {
+		self.offchain = Some(offchain);
+		self
+	}
+
+	/// Register the given transaction pool extension in the built externalities.
+	///
+	/// The submitted transactions can be read from the `PoolState` returned by
+	/// `TestTransactionPoolExt::new`.
+	pub fn transaction_pool(mut self, pool: TestTransactionPoolExt) -> Self {
+		self.transaction_pool = Some(pool);
+		self
+	}
+
+	/// Register the given keystore in the built externalities, e.g. a
+	/// `sp_keystore::testing::KeyStore`.
+	pub fn keystore(mut self, keystore: SyncCryptoStorePtr) -> Self {
+		self.keystore = Some(keystore);
+		self
+	}
+
+	/// Call `callback` with every progress report while downloading values, including the final
+	/// one, e.g. to render the progress in a custom way.
+	pub fn progress<F: Fn(&ProgressReport) + Send + Sync + 'static>(mut self, callback: F) -> Self {
+		self.progress.callback = Some(Arc::new(callback));
+		self
+	}
+
+	/// Draw a progress bar on stderr while downloading values, if it is a terminal.
+	///
+	/// Progress is logged either way. Defaults to `false`.
+	pub fn progress_bar(mut self, bar: bool) -> Self {
+		self.progress.bar = bar;
+		self
+	}
+
+	/// Use the given metadata to report the progress of downloading values per module.
+	pub fn metadata(mut self, metadata: Arc<Metadata>) -> Self {
+		self.modules = metadata
+			.storage_items()
+			.map(|(_, info)| (twox_128(info.module_prefix.as_bytes()), info.module_prefix.clone()))
+			.collect();
+		self
+	}
+
+	/// Configure what happens if the state root of the built externalities does not match the
+	/// one of the scraped block.
+	///
+	/// The check only happens if no module filter is set, and before any injections are applied.
+	/// Defaults to [`StateRootCheck::Fail`].
+	pub fn state_root_check(mut self, check: StateRootCheck) -> Self {
+		self.state_root_check = check;
 		self
 	}
 
@@ -428,6 +1541,15 @@ impl Builder {
 		self
 	}
 
+	/// Configure the directory in which the cache files are stored.
+	///
+	/// If not set, the [`CACHE_DIR_ENV`] environment variable is used, else the current
+	/// directory.
+	pub fn cache_dir<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+		self.cache_dir = Some(dir.into());
+		self
+	}
+
 	/// Configure the name of the cache file.
 	pub fn cache_name(mut self, name: CacheName) -> Self {
 		self.cache_name_config = name;
@@ -435,28 +1557,549 @@ impl Builder {
 	}
 
 	/// Build the test externalities.
-	pub async fn build(self) -> TestExternalities {
-		let kv = self.pre_build().await;
+	///
+	/// Any error that happens after the block to scrape is known is wrapped in
+	/// [`Error::Scrape`], which carries the block hash.
+	pub async fn build(mut self) -> Result<TestExternalities, Error> {
+		self.load_overrides()?;
 		let mut ext = TestExternalities::new_empty();
-
-		info!(target: LOG_TARGET, "injecting a total of {} keys", kv.len());
-		for (k, v) in kv {
-			let (k, v) = (k.0, v.0);
-			trace!(target: LOG_TARGET, "injecting {:?} -> {:?}", k.hex_display(), v.hex_display());
-			ext.insert(k, v);
+		let result = match self.pre_build(&mut ext).await {
+			Ok(()) => self.check_state_root(&mut ext),
+			Err(error) => Err(error),
+		};
+		if let Err(error) = result {
+			return Err(match self.at {
+				Some(at) => Error::Scrape { at, error: Box::new(error) },
+				None => error,
+			});
 		}
-		ext
+		ext.execute_with(|| self.apply_overrides());
+		ext.commit_all().expect("committing overrides to an in memory backend cannot fail; qed");
+		self.register_extensions(&mut ext);
+		Ok(ext)
+	}
+
+	/// Alias of [`Builder::build`].
+	pub async fn build_async(self) -> Result<TestExternalities, Error> {
+		self.build().await
+	}
+
+	/// Fetch the metadata of the runtime at the block of the builder, e.g. to pass it to
+	/// [`Builder::metadata`].
+	///
+	/// If no block is set, the latest finalized one is fetched, and used from then on. The
+	/// connection is kept, and used by the later build.
+	pub async fn remote_metadata(&mut self) -> Result<Arc<Metadata>, Error> {
+		self.connect().await?;
+		Ok(Arc::new(self.rpc_get_metadata(self.final_at()).await?))
+	}
+
+	/// Scrape the state into a new snapshot in the cache directory, and return its path.
+	///
+	/// Unlike [`Builder::build`], the state is neither loaded into externalities nor verified. The
+	/// cache mode is ignored, the snapshot is always created anew.
+	pub async fn snapshot(mut self) -> Result<PathBuf, Error> {
+		self.init_remote().await?;
+		let at = self.final_at();
+		let pairs = self
+			.scrape_remote()
+			.await
+			.map_err(|error| Error::Scrape { at, error: Box::new(error) })?;
+		self.save_cache(&pairs)?;
+		Ok(self.cache_path())
+	}
+
+	/// Build lazy externalities, that start empty and fetch every key that is read from the node.
+	///
+	/// This is much faster than scraping whole modules when a test only touches a few keys. All
+	/// the cache and filter configurations are ignored, but injections are applied. The fetched
+	/// keys can be saved via [`LazyExternalities::save`], and re-used offline.
+	///
+	/// Only connecting to the node and reading overrides files is fallible. Once built, a failing
+	/// request fails the storage access, and thus panics with the failed method and key, since the
+	/// runtime cannot handle storage errors.
+	pub async fn build_lazy(mut self) -> Result<LazyExternalities, Error> {
+		self.load_overrides()?;
+		self.init_remote().await?;
+		let header = SnapshotHeader { filter: vec!["lazy".into()], ..self.snapshot_header() };
+		let backend = LazyBackend::new(self.uri.clone(), self.final_at());
+		let mut ext = LazyExternalities::new(backend, header);
+		ext.execute_with(|| {
+			for (k, v) in &self.context {
+				sp_io::storage::set(&k.0, &v.0);
+			}
+			self.apply_overrides()
+		});
+		self.register_extensions(&mut ext);
+		Ok(ext)
 	}
 }
 
 #[cfg(test)]
 mod tests {
 	use super::*;
+	use snapshot::tests::test_header;
 	const TEST_URI: &'static str = "http://localhost:9933";
+	const TEST_WS_URI: &'static str = "ws://localhost:9944";
 
 	#[derive(Clone, Eq, PartialEq, Debug, Default)]
 	pub struct TestRuntime;
 
+	#[tokio::test]
+	async fn can_build_offline() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("offline.bin");
+		let pairs = vec![(StorageKey(b"key".to_vec()), StorageData(b"value".to_vec()))];
+		Snapshot::write(&path, &test_header(), &pairs).unwrap();
+
+		Builder::new()
+			.uri("http://invalid-uri-never-used:1".into())
+			.cache_mode(CacheMode::Offline { path: path.clone() })
+			.inject(&[(StorageKey(b"injected".to_vec()), StorageData(b"yes".to_vec()))])
+			.build()
+			.await
+			.unwrap()
+			.execute_with(|| {
+				assert_eq!(sp_io::storage::get(b"key"), Some(b"value".to_vec()));
+				assert_eq!(sp_io::storage::get(b"injected"), Some(b"yes".to_vec()));
+			});
+	}
+
+	fn state_root_of(pairs: &[KeyPair]) -> Hash {
+		let mut ext = TestExternalities::new_empty();
+		pairs.iter().for_each(|(k, v)| ext.insert(k.0.clone(), v.0.clone()));
+		Hash::from_slice(&ext.execute_with(|| sp_io::storage::root()))
+	}
+
+	fn full_state_snapshot(dir: &Path, state_root: Option<Hash>) -> PathBuf {
+		let path = dir.join("full.bin");
+		let pairs = vec![(StorageKey(b"key".to_vec()), StorageData(b"value".to_vec()))];
+		let state_root = state_root.unwrap_or_else(|| state_root_of(&pairs));
+		let header = SnapshotHeader { filter: vec![], state_root, ..test_header() };
+		Snapshot::write(&path, &header, &pairs).unwrap();
+		path
+	}
+
+	#[tokio::test]
+	async fn collect_pages_terminates() {
+		let all = (0u8..5).map(|i| StorageKey(vec![i])).collect::<Vec<_>>();
+		let pages = |page_size: usize| {
+			let all = all.clone();
+			move |start: Option<StorageKey>| {
+				let from = start.map_or(0, |k| k.0[0] as usize + 1);
+				let page = all.iter().skip(from).take(page_size).cloned().collect::<Vec<_>>();
+				async move { Ok(page) }
+			}
+		};
+
+		// the last page is shorter.
+		assert_eq!(collect_pages(2, pages(2)).await.unwrap(), all);
+		// the last page is full, and followed by an empty one.
+		assert_eq!(collect_pages(5, pages(5)).await.unwrap(), all);
+		// a page size of zero is clamped by the builder, but must not spin either.
+		assert_eq!(collect_pages(0, pages(1)).await.unwrap(), all);
+		assert_eq!(collect_pages(0, |_| async { Ok(vec![]) }).await.unwrap(), vec![]);
+	}
+
+	#[test]
+	fn incompatible_cache_is_rejected() {
+		let dir = tempfile::tempdir().unwrap();
+		let builder = |module: &str| {
+			let mut builder = Builder::new()
+				.module(module)
+				.cache_dir(dir.path())
+				.cache_name(CacheName::Forced("copied.bin".into()));
+			builder.at = Some(test_header().block_hash);
+			builder.chain = "Test".into();
+			builder.spec_version = 7;
+			builder
+		};
+		let pairs = [(StorageKey(vec![1]), StorageData(vec![2]))];
+		Snapshot::write(&builder("System").cache_path(), &test_header(), &pairs).unwrap();
+
+		let mut ext = TestExternalities::new_empty();
+		assert_eq!(builder("System").try_load_cached(&mut ext).unwrap(), 1);
+		assert!(matches!(
+			builder("Staking").try_load_cached(&mut ext),
+			Err(SnapshotError::Incompatible(_))
+		));
+		let mut other_block = builder("System");
+		other_block.at = Some(Hash::repeat_byte(9));
+		assert!(matches!(
+			other_block.try_load_cached(&mut ext),
+			Err(SnapshotError::Incompatible(_))
+		));
+		let mut other_runtime = builder("System");
+		other_runtime.spec_version = 8;
+		assert!(matches!(
+			other_runtime.try_load_cached(&mut ext),
+			Err(SnapshotError::Incompatible(_))
+		));
+	}
+
+	#[test]
+	fn refresh_keeps_fetches_and_removes() {
+		let key = |k: u8| StorageKey(vec![k]);
+		let value = |v: u8| StorageData(vec![v]);
+		let old = vec![(key(1), value(1)), (key(2), value(2)), (key(3), value(3))];
+		let (mut state, diff) =
+			diff_keys(old.into_iter().map(Ok), vec![key(4), key(3), key(1)]).unwrap();
+		assert_eq!(diff, KeyDiff { added: vec![key(4)], common: vec![key(1), key(3)], removed: 1 });
+		assert_eq!(state.keys().cloned().collect::<Vec<_>>(), vec![key(1), key(3)]);
+
+		let from = Hash::repeat_byte(1);
+		let change_set = |block: u8, changes: Vec<(StorageKey, Option<StorageData>)>| {
+			StorageChangeSet { block: Hash::repeat_byte(block), changes }
+		};
+		let change_sets = vec![
+			// the values at `from` are skipped, even if they differ.
+			change_set(1, vec![(key(1), Some(value(9))), (key(3), Some(value(3)))]),
+			change_set(2, vec![(key(3), None)]),
+			change_set(3, vec![(key(3), Some(value(33)))]),
+		];
+		assert_eq!(apply_changes(&mut state, from, change_sets), 1);
+		assert_eq!(
+			state.into_iter().collect::<Vec<_>>(),
+			vec![(key(1), value(1)), (key(3), value(33))]
+		);
+	}
+
+	#[tokio::test]
+	async fn refresh_rejects_other_chain() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("other.bin");
+		Snapshot::write(&path, &test_header(), &[]).unwrap();
+
+		let mut builder = Builder::new().uri("http://invalid-uri-never-used:1".into());
+		builder.at = Some(Hash::repeat_byte(9));
+		builder.chain = format!("not {}", test_header().chain);
+		assert!(matches!(
+			builder.refresh_remote(&path).await,
+			Err(Error::Cache { error: SnapshotError::Incompatible(_), .. })
+		));
+	}
+
+	#[tokio::test]
+	async fn block_context_works_offline() {
+		use codec::Decode;
+		use sp_runtime::{
+			generic::{Digest, DigestItem},
+			traits::Header as _,
+		};
+		let temp = tempfile::tempdir().unwrap();
+		let dir = temp.path();
+		let staking = StorageKey(sub_storage::module_prefix_raw(b"Staking", b"Ledger"));
+		let mut digest = Digest::<Hash>::default();
+		digest.push(DigestItem::Other(vec![1, 2, 3]));
+		let header = Header::new(
+			42,
+			Hash::repeat_byte(1),
+			Hash::repeat_byte(2),
+			Hash::repeat_byte(3),
+			digest.clone(),
+		);
+
+		let mut builder = Builder::new().module("Staking").block_context().cache_dir(dir);
+		builder.at = Some(Hash::repeat_byte(4));
+		builder.chain = "Test".into();
+		builder.context = Builder::block_context_pairs(&header, Some(StorageData(7u64.encode())));
+		builder.header = Some(header);
+		assert_eq!(builder.filter_names(), vec!["Staking".to_string(), BLOCK_CONTEXT.into()]);
+		let pairs = builder.add_block_context(vec![(staking.clone(), StorageData(vec![1]))]);
+		builder.save_cache(&pairs).unwrap();
+
+		let offline = |path: PathBuf| {
+			Builder::new().cache_mode(CacheMode::Offline { path }).block_context().build()
+		};
+		let get = |module: &[u8], item: &[u8]| {
+			sp_io::storage::get(&sub_storage::module_prefix_raw(module, item)).unwrap()
+		};
+		offline(builder.cache_path()).await.unwrap().execute_with(|| {
+			assert_eq!(u32::decode(&mut &*get(b"System", b"Number")).unwrap(), 42);
+			let parent_hash = Hash::decode(&mut &*get(b"System", b"ParentHash")).unwrap();
+			assert_eq!(parent_hash, Hash::repeat_byte(3));
+			assert_eq!(Digest::<Hash>::decode(&mut &*get(b"System", b"Digest")).unwrap(), digest);
+			assert_eq!(u64::decode(&mut &*get(b"Timestamp", b"Now")).unwrap(), 7);
+			assert_eq!(sp_io::storage::get(&staking.0), Some(vec![1]));
+		});
+
+		// a snapshot without the block context still gets the block number from its header.
+		let path = dir.join("without-context.bin");
+		let header = SnapshotHeader { filter: vec!["Staking".into()], ..test_header() };
+		Snapshot::write(&path, &header, &[(staking, StorageData(vec![1]))]).unwrap();
+		offline(path).await.unwrap().execute_with(|| {
+			assert_eq!(u32::decode(&mut &*get(b"System", b"Number")).unwrap(), 42);
+			assert!(sp_io::storage::get(&sub_storage::module_prefix_raw(b"Timestamp", b"Now"))
+				.is_none());
+		});
+	}
+
+	#[tokio::test]
+	async fn extensions_are_registered() {
+		use sp_keystore::SyncCryptoStore;
+		let key_type = sp_core::crypto::KeyTypeId(*b"test");
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("extensions.bin");
+		Snapshot::write(&path, &test_header(), &[]).unwrap();
+
+		let (offchain, offchain_state) = TestOffchainExt::new();
+		offchain_state.write().seed = [7; 32];
+		let (pool, pool_state) = TestTransactionPoolExt::new();
+		let keystore = std::sync::Arc::new(sp_keystore::testing::KeyStore::new());
+		Builder::new()
+			.cache_mode(CacheMode::Offline { path: path.clone() })
+			.offchain(offchain)
+			.transaction_pool(pool)
+			.keystore(keystore.clone())
+			.build()
+			.await
+			.unwrap()
+			.execute_with(|| {
+				assert_eq!(sp_io::offchain::random_seed(), [7; 32]);
+				sp_io::offchain::submit_transaction(vec![42]).unwrap();
+				sp_io::crypto::sr25519_generate(key_type, None);
+			});
+		assert_eq!(pool_state.read().transactions, vec![vec![42]]);
+		assert_eq!(SyncCryptoStore::sr25519_public_keys(&*keystore, key_type).len(), 1);
+	}
+
+	#[cfg(feature = "executor")]
+	#[test]
+	fn executor_needs_runtime_code() {
+		let mut ext = TestExternalities::new_empty();
+		assert!(matches!(RuntimeExecutor::from_state(&mut ext), Err(Error::NoRuntimeCode)));
+	}
+
+	#[tokio::test]
+	async fn state_root_check_works() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = full_state_snapshot(dir.path(), None);
+		Builder::new()
+			.cache_mode(CacheMode::Offline { path: path.clone() })
+			.inject(&[(StorageKey(b"injected".to_vec()), StorageData(b"yes".to_vec()))])
+			.build()
+			.await
+			.unwrap()
+			.execute_with(|| assert_eq!(sp_io::storage::get(b"key"), Some(b"value".to_vec())));
+	}
+
+	#[tokio::test]
+	async fn state_root_mismatch_can_warn() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = full_state_snapshot(dir.path(), Some(Hash::zero()));
+		Builder::new()
+			.cache_mode(CacheMode::Offline { path: path.clone() })
+			.state_root_check(StateRootCheck::Warn)
+			.build()
+			.await
+			.unwrap()
+			.execute_with(|| assert_eq!(sp_io::storage::get(b"key"), Some(b"value".to_vec())));
+	}
+
+	#[tokio::test]
+	async fn state_root_mismatch_fails() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = full_state_snapshot(dir.path(), Some(Hash::zero()));
+		let result =
+			Builder::new().cache_mode(CacheMode::Offline { path: path.clone() }).build().await;
+		match result {
+			Err(Error::Scrape { at, error }) => {
+				assert_eq!(at, test_header().block_hash);
+				assert!(matches!(*error, Error::StateRootMismatch { .. }));
+			}
+			_ => panic!("expected a state root mismatch"),
+		}
+	}
+
+	#[tokio::test]
+	async fn missing_snapshot_fails() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("does-not-exist.bin");
+		let result =
+			Builder::new().cache_mode(CacheMode::Offline { path: path.clone() }).build().await;
+		match result {
+			Err(Error::Cache { path: error_path, error: SnapshotError::Io(_) }) => {
+				assert_eq!(error_path, path)
+			}
+			_ => panic!("expected a cache error"),
+		}
+	}
+
+	#[tokio::test]
+	async fn connection_error_is_reported() {
+		let result = Builder::new().uri("http://localhost:1".into()).build().await;
+		assert!(matches!(result, Err(Error::Connection { .. })));
+	}
+
+	#[tokio::test]
+	#[ignore = "needs remove node"]
+	async fn can_build_over_ws() {
+		Builder::new()
+			.uri(TEST_WS_URI.into())
+			.module("System")
+			.build()
+			.await
+			.unwrap()
+			.execute_with(|| {});
+	}
+
+	#[test]
+	fn filters_work() {
+		let builder = Builder::new()
+			.module("Staking")
+			.storage_item("System", "Account")
+			.prefix(&[1, 2])
+			.exclude(&[1, 2, 3])
+			.exclude_item("Staking", "Ledger");
+
+		assert_eq!(
+			builder.filter_names()[..4],
+			["Staking".to_string(), "System::Account".into(), "0x0102".into(), "!0x010203".into()]
+		);
+		assert!(!builder.is_full_scrape());
+		assert!(builder.is_excluded(&StorageKey(vec![1, 2, 3, 4])));
+		assert!(!builder.is_excluded(&StorageKey(vec![1, 2, 4])));
+
+		let ledger = sub_storage::module_prefix_raw(b"Staking", b"Ledger");
+		assert!(builder.is_excluded(&StorageKey([ledger, vec![0u8; 32]].concat())));
+		assert_eq!(
+			Filter::StorageItem { module: "Staking".into(), item: "Ledger".into() }.prefix(),
+			Some(StorageKey(sub_storage::module_prefix_raw(b"Staking", b"Ledger")))
+		);
+	}
+
+	#[test]
+	fn map_keys_filter_works() {
+		use sub_storage::entry::{Map, StorageHasher};
+		const LEDGER: StorageEntry<Map<u32>, u32> =
+			StorageEntry::new("Staking", "Ledger", &[StorageHasher::Twox64Concat]);
+
+		let builder = Builder::new().map_keys(LEDGER, &[1, 2]);
+		match &builder.filters[0] {
+			Filter::Keys { name, keys } => {
+				assert_eq!(name, "Staking::Ledger");
+				assert_eq!(keys, &vec![LEDGER.key(&1), LEDGER.key(&2)]);
+			}
+			_ => panic!("expected a keys filter"),
+		}
+		// different keys of the same item end up in different caches.
+		assert_ne!(builder.filter_names(), Builder::new().map_keys(LEDGER, &[3]).filter_names());
+	}
+
+	#[tokio::test]
+	#[ignore = "needs remove node"]
+	async fn can_build_storage_item() {
+		Builder::new()
+			.uri(TEST_URI.into())
+			.storage_item("System", "Number")
+			.build()
+			.await
+			.unwrap()
+			.execute_with(|| {
+				let key = sub_storage::module_prefix_raw(b"System", b"Number");
+				assert!(sp_io::storage::get(&key).is_some());
+			});
+	}
+
+	#[tokio::test]
+	#[ignore = "needs remove node"]
+	async fn can_build_with_block_context() {
+		let number_key = sub_storage::module_prefix_raw(b"System", b"Number");
+		let now_key = sub_storage::module_prefix_raw(b"Timestamp", b"Now");
+		let mut builder = Builder::new().uri(TEST_URI.into()).module("Staking").block_context();
+		builder.init_remote().await.unwrap();
+		let expected = builder.header.as_ref().unwrap().number;
+
+		let mut ext = TestExternalities::new_empty();
+		builder.pre_build(&mut ext).await.unwrap();
+		ext.execute_with(|| {
+			let number = sp_io::storage::get(&number_key).unwrap();
+			assert_eq!(<u32 as codec::Decode>::decode(&mut &*number).unwrap(), expected);
+			assert!(sp_io::storage::get(&now_key).is_some());
+		});
+	}
+
+	#[cfg(feature = "executor")]
+	#[tokio::test]
+	#[ignore = "needs remove node"]
+	async fn can_execute_runtime() {
+		let mut ext = Builder::new()
+			.uri(TEST_URI.into())
+			.prefix(sp_core::storage::well_known_keys::CODE)
+			.build()
+			.await
+			.unwrap();
+		let runtime = RuntimeExecutor::from_state(&mut ext).unwrap();
+		assert!(runtime.version(&mut ext).unwrap().spec_version > 0);
+		assert!(!runtime.metadata(&mut ext).unwrap().is_empty());
+		assert!(matches!(
+			runtime.call(&mut ext, "Nonexistent_api", &[]),
+			Err(Error::Runtime { .. })
+		));
+	}
+
+	#[tokio::test]
+	#[ignore = "needs remove node"]
+	async fn can_build_lazy() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("lazy.bin");
+		let number_key = sub_storage::module_prefix_raw(b"System", b"Number");
+		let mut ext = Builder::new().uri(TEST_URI.into()).build_lazy().await.unwrap();
+		let number = ext.execute_with(|| sp_io::storage::get(&number_key));
+		assert!(number.is_some());
+		ext.save(&path).unwrap();
+
+		Builder::new()
+			.cache_mode(CacheMode::Offline { path: path.clone() })
+			.build()
+			.await
+			.unwrap()
+			.execute_with(|| assert_eq!(sp_io::storage::get(&number_key), number));
+	}
+
+	#[tokio::test]
+	#[ignore = "needs remove node"]
+	async fn can_follow_chain() {
+		let temp = tempfile::tempdir().unwrap();
+		let dir = temp.path();
+		let builder =
+			Builder::new().uri(TEST_WS_URI.into()).storage_item("System", "Number").cache_dir(dir);
+		let mut calls = 0;
+		let mut sidecar = sidecar::Sidecar::new(builder, |_| calls += 1).await.unwrap();
+		let number_key = sub_storage::module_prefix_raw(b"System", b"Number");
+		let start = sidecar.ext().execute_with(|| sp_io::storage::get(&number_key)).unwrap();
+		let start = <u32 as codec::Decode>::decode(&mut &*start).unwrap();
+
+		let report = sidecar.next_block().await.unwrap();
+		assert_eq!(report.block.number, start + 1);
+		// the hook does nothing, so the block number diverges.
+		assert_eq!(report.divergence.counts(), (0, 0, 1));
+		assert!(sidecar.save().await.unwrap().exists());
+		drop(sidecar);
+		assert_eq!(calls, 1);
+	}
+
+	#[tokio::test]
+	#[ignore = "needs remove node"]
+	async fn can_refresh_cache() {
+		let temp = tempfile::tempdir().unwrap();
+		let dir = temp.path();
+		let builder = || Builder::new().uri(TEST_URI.into()).module("System").cache_dir(dir);
+		let mut old = builder();
+		old.init_remote().await.unwrap();
+		let old_pairs = old.scrape_remote().await.unwrap();
+		old.save_cache(&old_pairs).unwrap();
+
+		// refresh to the latest finalized block.
+		let mut new = builder();
+		new.init_remote().await.unwrap();
+		let mut refreshed = new.refresh_remote(&old.cache_path()).await.unwrap();
+		let mut scraped = new.scrape_remote().await.unwrap();
+		scraped.sort();
+		refreshed.sort();
+		assert_eq!(refreshed, scraped);
+	}
+
 	#[tokio::test]
 	#[ignore = "needs remove node"]
 	async fn can_build_system() {
@@ -465,7 +2108,13 @@ mod tests {
 			.format_level(true)
 			.try_init();
 
-		Builder::new().uri(TEST_URI.into()).module("System").build().await.execute_with(|| {});
+		Builder::new()
+			.uri(TEST_URI.into())
+			.module("System")
+			.build()
+			.await
+			.unwrap()
+			.execute_with(|| {});
 	}
 
 	#[tokio::test]
@@ -482,9 +2131,10 @@ mod tests {
 			.module("System")
 			.build()
 			.await
+			.unwrap()
 			.execute_with(|| {});
 
-		let to_delete = std::fs::read_dir(Builder::cache_dir())
+		let to_delete = std::fs::read_dir(Builder::new().final_cache_dir())
 			.unwrap()
 			.into_iter()
 			.map(|d| d.unwrap())
@@ -511,6 +2161,7 @@ mod tests {
 			.cache_mode(CacheMode::UseElseCreate)
 			.build()
 			.await
+			.unwrap()
 			.execute_with(|| {});
 	}
 }
@@ -0,0 +1,265 @@
+//! Managing a directory of snapshots, as used by the `remote-ext` binary.
+//!
+//! Caches tend to pile up, with names such as `Kusama,0x7f13..,Staking.bin` that say little about
+//! their content. The functions here read what is needed from the snapshots themselves:
+//!
+//! - [`list`] the snapshots of a directory, with their header, size and number of keys.
+//! - [`inspect`] the keys of a snapshot, grouped by storage item.
+//! - [`merge`] snapshots of the same block into one.
+//! - [`subset`] a snapshot, keeping only the keys that match some [`Filter`]s.
+
+use crate::{
+	export::UNKNOWN, snapshot::DEFAULT_COMPRESSION_LEVEL, Filter, KeyPair, Snapshot, SnapshotError,
+	SnapshotHeader, SnapshotReader, SnapshotWriter, LOG_TARGET,
+};
+use log::*;
+use sp_core::{hexdisplay::HexDisplay, storage::StorageKey};
+use std::{
+	collections::BTreeMap,
+	fs,
+	path::{Path, PathBuf},
+};
+use sub_storage::metadata::Metadata;
+
+/// The extension of snapshot files.
+pub const EXTENSION: &str = "bin";
+
+/// A snapshot file, and what it contains.
+#[derive(Debug, Clone)]
+pub struct SnapshotInfo {
+	/// The path to the snapshot.
+	pub path: PathBuf,
+	/// The header of the snapshot.
+	pub header: SnapshotHeader,
+	/// The size of the file, in bytes.
+	pub size: u64,
+	/// The number of keys in the snapshot.
+	pub keys: usize,
+}
+
+impl SnapshotInfo {
+	/// Read the info of the snapshot at `path`.
+	///
+	/// Counting the keys requires decompressing the whole snapshot, but the pairs are never all
+	/// held in memory.
+	pub fn read(path: &Path) -> Result<Self, SnapshotError> {
+		let mut reader = SnapshotReader::open(path)?;
+		let header = reader.header().clone();
+		let keys = reader.try_fold(0, |count, pair| pair.map(|_| count + 1))?;
+		let size = fs::metadata(path)?.len();
+		Ok(Self { path: path.to_path_buf(), header, size, keys })
+	}
+}
+
+/// All the snapshots in `dir`, sorted by chain, block number and path.
+///
+/// Files that are not snapshots, including caches of the legacy format, are skipped.
+pub fn list(dir: &Path) -> Result<Vec<SnapshotInfo>, SnapshotError> {
+	let mut snapshots = Vec::new();
+	for entry in fs::read_dir(dir)? {
+		let path = entry?.path();
+		if path.extension().map_or(true, |e| e != EXTENSION) {
+			continue;
+		}
+		match SnapshotInfo::read(&path) {
+			Ok(info) => snapshots.push(info),
+			Err(why) => debug!(target: LOG_TARGET, "skipping {:?}: {}", path, why),
+		}
+	}
+	snapshots.sort_by_key(|i| (i.header.chain.clone(), i.header.block_number, i.path.clone()));
+	Ok(snapshots)
+}
+
+/// The storage item of `key`, as `(module, item)`.
+///
+/// If the key cannot be identified with `metadata`, the hex encoded hashes of the module and item
+/// are used instead, if the key is long enough, else `(UNKNOWN, "")`.
+pub(crate) fn item_of(key: &StorageKey, metadata: Option<&Metadata>) -> (String, String) {
+	if let Some(info) = metadata.and_then(|m| m.storage_of_key(&key.0)) {
+		return (info.module_prefix.clone(), info.name.clone());
+	}
+	if key.0.len() >= 32 {
+		(
+			format!("0x{}", HexDisplay::from(&&key.0[..16])),
+			format!("0x{}", HexDisplay::from(&&key.0[16..32])),
+		)
+	} else {
+		(UNKNOWN.to_string(), String::new())
+	}
+}
+
+/// The absolute path of `path`, with all symlinks resolved.
+///
+/// `path` itself does not need to exist, as long as its parent directory does, so that this can be
+/// used for outputs that are yet to be created.
+pub fn canonical(path: &Path) -> Result<PathBuf, SnapshotError> {
+	if path.exists() {
+		return Ok(fs::canonicalize(path)?);
+	}
+	let parent = match path.parent() {
+		Some(parent) if !parent.as_os_str().is_empty() => fs::canonicalize(parent)?,
+		_ => std::env::current_dir()?,
+	};
+	Ok(parent.join(path.file_name().unwrap_or_default()))
+}
+
+/// Fail if `output` is one of `inputs`, as creating it would truncate the input.
+fn ensure_distinct(inputs: &[PathBuf], output: &Path) -> Result<(), SnapshotError> {
+	let canonical_output = canonical(output)?;
+	for input in inputs {
+		if canonical(input)? == canonical_output {
+			return Err(SnapshotError::Incompatible(format!(
+				"{:?} is both an input and the output",
+				input
+			)));
+		}
+	}
+	Ok(())
+}
+
+/// The number of keys, and total size of values in bytes, of each storage item of a snapshot.
+pub fn inspect(
+	path: &Path,
+	metadata: Option<&Metadata>,
+) -> Result<BTreeMap<(String, String), (usize, usize)>, SnapshotError> {
+	let mut items = BTreeMap::<_, (usize, usize)>::new();
+	for pair in SnapshotReader::open(path)? {
+		let (key, value) = pair?;
+		let entry = items.entry(item_of(&key, metadata)).or_default();
+		entry.0 += 1;
+		entry.1 += value.0.len();
+	}
+	Ok(items)
+}
+
+/// Merge the snapshots at `inputs` into a new one at `output`.
+///
+/// All inputs must be of the same block. If a key exists in more than one input, the value of the
+/// last one is kept. The merged snapshot is a full scrape if any of the inputs is, else its filter
+/// is the union of the filters of all inputs. `output` must not be one of the inputs.
+pub fn merge(inputs: &[PathBuf], output: &Path) -> Result<SnapshotHeader, SnapshotError> {
+	let first =
+		inputs.first().ok_or_else(|| SnapshotError::Incompatible("nothing to merge".into()))?;
+	ensure_distinct(inputs, output)?;
+
+	let mut header = Snapshot::load_header(first)?;
+	let mut pairs = BTreeMap::new();
+	for path in inputs {
+		let reader = SnapshotReader::open(path)?;
+		let other = reader.header();
+		if (&other.chain, other.block_hash) != (&header.chain, header.block_hash) {
+			return Err(SnapshotError::Incompatible(format!(
+				"{:?} is of {} at {:?}, expected {} at {:?}",
+				path, other.chain, other.block_hash, header.chain, header.block_hash
+			)));
+		}
+		if other.filter.is_empty() || header.filter.is_empty() {
+			header.filter.clear();
+		} else {
+			for filter in &other.filter {
+				if !header.filter.contains(filter) {
+					header.filter.push(filter.clone());
+				}
+			}
+		}
+		for pair in reader {
+			let (key, value) = pair?;
+			pairs.insert(key, value);
+		}
+	}
+
+	header.created_at = SnapshotHeader::now();
+	Snapshot::write(output, &header, &pairs.into_iter().collect::<Vec<KeyPair>>())?;
+	Ok(header)
+}
+
+/// Write the keys of the snapshot at `input` that match any of `filters` into a new snapshot at
+/// `output`, and return the number of keys written.
+///
+/// The filter of the new snapshot is the description of `filters`, so it is never verified against
+/// the state root. `filters` should thus not be empty, and `output` must not be `input`.
+pub fn subset(input: &Path, output: &Path, filters: &[Filter]) -> Result<usize, SnapshotError> {
+	ensure_distinct(&[input.to_path_buf()], output)?;
+	let reader = SnapshotReader::open(input)?;
+	let header = SnapshotHeader {
+		filter: filters.iter().map(|f| f.describe()).collect(),
+		created_at: SnapshotHeader::now(),
+		..reader.header().clone()
+	};
+	let mut writer = SnapshotWriter::create(output, &header, DEFAULT_COMPRESSION_LEVEL)?;
+	for pair in reader {
+		let (key, value) = pair?;
+		if filters.iter().any(|f| f.matches(&key)) {
+			writer.write_pair(&key.0, &value.0)?;
+		}
+	}
+	writer.finish()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{snapshot::tests::test_header, Hash};
+	use sp_core::storage::StorageData;
+
+	#[test]
+	fn library_works() {
+		let temp = tempfile::tempdir().unwrap();
+		let dir = temp.path();
+		let system = sub_storage::module_prefix_raw(b"System", b"Number");
+		let staking = sub_storage::module_prefix_raw(b"Staking", b"Ledger");
+		let a = dir.join("a.bin");
+		let b = dir.join("b.bin");
+		Snapshot::write(
+			&a,
+			&test_header(),
+			&[
+				(StorageKey(system.clone()), StorageData(vec![1])),
+				(StorageKey(vec![1]), StorageData(vec![])),
+			],
+		)
+		.unwrap();
+		let header = SnapshotHeader { filter: vec!["Staking".into()], ..test_header() };
+		Snapshot::write(&b, &header, &[(StorageKey(staking.clone()), StorageData(vec![2, 3]))])
+			.unwrap();
+		std::fs::write(dir.join("legacy.bin"), b"not a snapshot").unwrap();
+
+		let listed = list(dir).unwrap();
+		assert_eq!(listed.iter().map(|i| i.keys).collect::<Vec<_>>(), vec![2, 1]);
+
+		let merged = dir.join("merged.bin");
+		let header = merge(&[a.clone(), b.clone()], &merged).unwrap();
+		assert_eq!(header.filter, vec!["System".to_string(), "Staking".to_string()]);
+		assert_eq!(Snapshot::load(&merged).unwrap().pairs.len(), 3);
+
+		let items = inspect(&merged, None).unwrap();
+		assert_eq!(items.len(), 3);
+		assert_eq!(items[&(UNKNOWN.to_string(), String::new())], (1, 0));
+
+		let subset = dir.join("subset.bin");
+		let filters = vec![Filter::StorageItem { module: "Staking".into(), item: "Ledger".into() }];
+		assert_eq!(subset(&merged, &subset, &filters).unwrap(), 1);
+		let snapshot = Snapshot::load(&subset).unwrap();
+		assert_eq!(snapshot.header.filter, vec!["Staking::Ledger".to_string()]);
+		assert_eq!(snapshot.pairs, vec![(StorageKey(staking), StorageData(vec![2, 3]))]);
+
+		let other_block = dir.join("other.bin");
+		let header = SnapshotHeader { block_hash: Hash::repeat_byte(9), ..test_header() };
+		Snapshot::write(&other_block, &header, &[]).unwrap();
+		assert!(matches!(
+			merge(&[a.clone(), other_block], &merged),
+			Err(SnapshotError::Incompatible(_))
+		));
+		assert!(matches!(
+			merge(&[a.clone(), b], &dir.join(".").join("a.bin")),
+			Err(SnapshotError::Incompatible(_))
+		));
+		assert!(matches!(subset(&subset, &subset, &filters), Err(SnapshotError::Incompatible(_))));
+		assert_eq!(Snapshot::load(&subset).unwrap().pairs.len(), 1);
+		assert_eq!(Snapshot::load(&a).unwrap().pairs.len(), 2);
+		assert_eq!(
+			canonical(&dir.join("new.bin")).unwrap(),
+			std::fs::canonicalize(dir).unwrap().join("new.bin")
+		);
+	}
+}
@@ -0,0 +1,360 @@
+//! `remote-ext`: manage the snapshots created by remote externalities.
+//!
+//! ```text
+//! remote-ext snapshot create --uri wss://kusama-rpc.polkadot.io --module Staking
+//! remote-ext snapshot list
+//! remote-ext snapshot inspect "Kusama,0x7f13..,Staking.bin" --uri wss://kusama-rpc.polkadot.io
+//! remote-ext snapshot compare before.bin after.bin --uri wss://kusama-rpc.polkadot.io
+//! remote-ext snapshot merge a.bin b.bin --output ab.bin
+//! remote-ext snapshot subset full.bin --item System::Account --output accounts.bin
+//! remote-ext snapshot delete --older-than 30
+//! remote-ext call "Kusama,0x7f13..,.bin" Core_version
+//! ```
+//!
+//! `call` is only available with the `executor` feature.
+//!
+//! `create`, `list` and `delete --older-than` work on the directory given by `--dir`, else the one
+//! in the `REMOTE_EXT_CACHE_DIR` environment variable, else the current directory.
+
+use remote_externalities::{
+	diff::StateDiff, library, Builder, CacheName, Error, Filter, Snapshot, SnapshotHeader,
+	CACHE_DIR_ENV,
+};
+use std::{collections::BTreeSet, fs, path::PathBuf, sync::Arc};
+use sub_storage::metadata::Metadata;
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "remote-ext", about = "manage the snapshots of remote externalities")]
+struct Opt {
+	/// The directory of the snapshots.
+	#[structopt(long, parse(from_os_str))]
+	dir: Option<PathBuf>,
+
+	/// The subcommand.
+	#[structopt(subcommand)]
+	cmd: Command,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+	/// Create, list and edit snapshots.
+	Snapshot(SnapshotCmd),
+	/// Call a runtime api of the Wasm runtime, against the state of a snapshot.
+	#[cfg(feature = "executor")]
+	Call {
+		/// The snapshot.
+		#[structopt(parse(from_os_str))]
+		snapshot: PathBuf,
+		/// The runtime api, e.g. `Core_version`.
+		method: String,
+		/// The hex encoded, scale encoded, arguments.
+		#[structopt(long, default_value = "0x")]
+		data: String,
+		/// A Wasm runtime to use instead of the `:code` of the snapshot.
+		#[structopt(long, parse(from_os_str))]
+		wasm: Option<PathBuf>,
+	},
+}
+
+#[derive(Debug, StructOpt)]
+enum SnapshotCmd {
+	/// Scrape a new snapshot from a node.
+	Create {
+		/// The node to connect to.
+		#[structopt(long, default_value = "ws://localhost:9944")]
+		uri: String,
+		/// The block at which to scrape, else the latest finalized one.
+		#[structopt(long)]
+		at: Option<sp_core::H256>,
+		#[structopt(flatten)]
+		filters: FilterOpt,
+		/// The file name of the snapshot, else derived from the chain, block and filters.
+		#[structopt(long)]
+		name: Option<String>,
+	},
+	/// List all snapshots, with their header.
+	List,
+	/// Show the number of keys and size of each storage item of a snapshot.
+	Inspect {
+		/// The snapshot.
+		#[structopt(parse(from_os_str))]
+		path: PathBuf,
+		/// A node from which the metadata is fetched, to name the storage items.
+		#[structopt(long)]
+		uri: Option<String>,
+	},
+	/// Compare two snapshots key by key, e.g. before and after a migration.
+	Compare {
+		/// The original snapshot.
+		#[structopt(parse(from_os_str))]
+		before: PathBuf,
+		/// The changed snapshot.
+		#[structopt(parse(from_os_str))]
+		after: PathBuf,
+		/// A node from which the metadata of `after` is fetched, to name the storage items and
+		/// decode the values.
+		#[structopt(long)]
+		uri: Option<String>,
+		/// The maximum number of changes printed per storage item.
+		#[structopt(long, default_value = "5")]
+		max_examples: usize,
+	},
+	/// Merge snapshots of the same block into one.
+	Merge {
+		/// The snapshots to merge. On conflicts, the value of the last one is kept.
+		#[structopt(parse(from_os_str), required = true, min_values = 2)]
+		inputs: Vec<PathBuf>,
+		/// The merged snapshot.
+		#[structopt(long, short, parse(from_os_str))]
+		output: PathBuf,
+	},
+	/// Keep only some keys of a snapshot.
+	Subset {
+		/// The original snapshot.
+		#[structopt(parse(from_os_str))]
+		input: PathBuf,
+		#[structopt(flatten)]
+		filters: FilterOpt,
+		/// The new snapshot.
+		#[structopt(long, short, parse(from_os_str))]
+		output: PathBuf,
+	},
+	/// Delete snapshots.
+	Delete {
+		/// The snapshots to delete.
+		#[structopt(parse(from_os_str))]
+		paths: Vec<PathBuf>,
+		/// Delete all snapshots created more than this many days ago.
+		#[structopt(long)]
+		older_than: Option<u64>,
+		/// Only delete the snapshots of this chain.
+		#[structopt(long)]
+		chain: Option<String>,
+		/// Only print what would be deleted.
+		#[structopt(long)]
+		dry_run: bool,
+	},
+}
+
+#[derive(Debug, StructOpt)]
+struct FilterOpt {
+	/// Modules to keep.
+	#[structopt(long = "module")]
+	modules: Vec<String>,
+	/// Storage items to keep, as `Module::Item`.
+	#[structopt(long = "item")]
+	items: Vec<String>,
+	/// Raw, hex encoded, prefixes to keep.
+	#[structopt(long = "prefix")]
+	prefixes: Vec<String>,
+}
+
+impl FilterOpt {
+	fn filters(&self) -> Result<Vec<Filter>, String> {
+		let mut filters = self.modules.iter().cloned().map(Filter::Module).collect::<Vec<_>>();
+		for item in &self.items {
+			let mut split = item.splitn(2, "::");
+			match (split.next(), split.next()) {
+				(Some(module), Some(item)) => {
+					filters.push(Filter::StorageItem { module: module.into(), item: item.into() })
+				}
+				_ => return Err(format!("invalid storage item {}, expected Module::Item", item)),
+			}
+		}
+		for prefix in &self.prefixes {
+			let prefix = sp_core::bytes::from_hex(prefix)
+				.map_err(|e| format!("invalid prefix {}: {}", prefix, e))?;
+			filters.push(Filter::Prefix(prefix));
+		}
+		Ok(filters)
+	}
+}
+
+struct Size(u64);
+
+impl std::fmt::Display for Size {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		const UNITS: [&str; 4] = ["B", "K", "M", "G"];
+		let mut size = self.0 as f64;
+		let mut unit = 0;
+		while size >= 1024.0 && unit < UNITS.len() - 1 {
+			size /= 1024.0;
+			unit += 1;
+		}
+		write!(f, "{:.1}{}", size, UNITS[unit])
+	}
+}
+
+/// The metadata at block `at`, if a node to fetch it from is given.
+async fn metadata_at(
+	uri: Option<String>,
+	at: sp_core::H256,
+) -> Result<Option<Arc<Metadata>>, Error> {
+	match uri {
+		Some(uri) => Ok(Some(Builder::new().uri(uri).at(at).remote_metadata().await?)),
+		None => Ok(None),
+	}
+}
+
+async fn snapshot(dir: PathBuf, cmd: SnapshotCmd) -> Result<(), Box<dyn std::error::Error>> {
+	match cmd {
+		SnapshotCmd::Create { uri, at, filters, name } => {
+			let mut builder = Builder::new().uri(uri).cache_dir(&dir).progress_bar(true);
+			if let Some(at) = at {
+				builder = builder.at(at);
+			}
+			let metadata = builder.remote_metadata().await?;
+			builder = builder.metadata(metadata);
+			if let Some(name) = name {
+				builder = builder.cache_name(CacheName::Forced(name));
+			}
+			for filter in filters.filters()? {
+				builder = match filter {
+					Filter::Module(module) => builder.module(&module),
+					Filter::StorageItem { module, item } => builder.storage_item(&module, &item),
+					Filter::Prefix(prefix) => builder.prefix(&prefix),
+					Filter::Keys { .. } => unreachable!("keys are never parsed from the cli; qed"),
+				};
+			}
+			let path = builder.snapshot().await?;
+			println!("created {}", path.display());
+		}
+		SnapshotCmd::List => {
+			for info in library::list(&dir)? {
+				let header = info.header;
+				println!(
+					"{}\n\t{} #{} ({:?}), spec version {}, {} keys, {}, filter: [{}]",
+					info.path.display(),
+					header.chain,
+					header.block_number,
+					header.block_hash,
+					header.spec_version,
+					info.keys,
+					Size(info.size),
+					header.filter.join(", "),
+				);
+			}
+		}
+		SnapshotCmd::Inspect { path, uri } => {
+			let header = Snapshot::load_header(&path)?;
+			let metadata = metadata_at(uri, header.block_hash).await?;
+			println!(
+				"{} #{} ({:?}), spec version {}",
+				header.chain, header.block_number, header.block_hash, header.spec_version
+			);
+			let items = library::inspect(&path, metadata.as_deref())?;
+			let mut modules = Vec::<(&str, usize, usize)>::new();
+			for ((module, _), (keys, size)) in items.iter() {
+				match modules.last_mut() {
+					Some(last) if last.0 == module.as_str() => {
+						last.1 += keys;
+						last.2 += size;
+					}
+					_ => modules.push((module.as_str(), *keys, *size)),
+				}
+			}
+			for (module, keys, size) in modules {
+				println!("{} ({} keys, {})", module, keys, Size(size as u64));
+				for ((_, item), (keys, size)) in items.iter().filter(|((m, _), _)| m == module) {
+					println!("\t{} ({} keys, {})", item, keys, Size(*size as u64));
+				}
+			}
+		}
+		SnapshotCmd::Compare { before, after, uri, max_examples } => {
+			let metadata = metadata_at(uri, Snapshot::load_header(&after)?.block_hash).await?;
+			let diff = StateDiff::of_snapshots(&before, &after, metadata.as_deref())?;
+			if diff.is_empty() {
+				println!("no changes");
+			} else {
+				print!("{}", diff.report(metadata.as_deref(), max_examples));
+			}
+		}
+		SnapshotCmd::Merge { inputs, output } => {
+			let header = library::merge(&inputs, &output)?;
+			println!(
+				"merged {} snapshots into {} [{}]",
+				inputs.len(),
+				output.display(),
+				header.filter.join(", ")
+			);
+		}
+		SnapshotCmd::Subset { input, filters, output } => {
+			let filters = filters.filters()?;
+			if filters.is_empty() {
+				return Err("at least one of --module, --item or --prefix must be given".into());
+			}
+			let count = library::subset(&input, &output, &filters)?;
+			println!("wrote {} keys to {}", count, output.display());
+		}
+		SnapshotCmd::Delete { paths, older_than, chain, dry_run } => {
+			let paths = match older_than {
+				Some(days) => {
+					let deadline = SnapshotHeader::now().saturating_sub(days * 24 * 60 * 60);
+					library::list(&dir)?
+						.into_iter()
+						.filter(|info| info.header.created_at < deadline)
+						.filter(|info| chain.as_ref().map_or(true, |c| c == &info.header.chain))
+						.map(|info| info.path)
+						.chain(paths)
+						.collect()
+				}
+				None if paths.is_empty() => {
+					return Err("either snapshot paths or --older-than must be given".into())
+				}
+				None => paths,
+			};
+			// the same snapshot may be both given and too old, possibly through different paths.
+			let mut seen = BTreeSet::new();
+			for path in paths {
+				if !seen.insert(library::canonical(&path)?) {
+					continue;
+				}
+				println!("deleting {}", path.display());
+				if !dry_run {
+					fs::remove_file(path)?;
+				}
+			}
+		}
+	}
+	Ok(())
+}
+
+#[cfg(feature = "executor")]
+async fn call(
+	snapshot: PathBuf,
+	method: String,
+	data: String,
+	wasm: Option<PathBuf>,
+) -> Result<(), Box<dyn std::error::Error>> {
+	use remote_externalities::{CacheMode, RuntimeExecutor};
+	let data = sp_core::bytes::from_hex(&data).map_err(|e| format!("invalid data: {}", e))?;
+	let mut ext = Builder::new().cache_mode(CacheMode::Offline { path: snapshot }).build().await?;
+	let runtime = match wasm {
+		Some(path) => RuntimeExecutor::from_file(&path)?,
+		None => RuntimeExecutor::from_state(&mut ext)?,
+	};
+	let result = runtime.call(&mut ext, &method, &data)?;
+	println!("0x{}", sp_core::hexdisplay::HexDisplay::from(&result));
+	Ok(())
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+	env_logger::Builder::from_default_env().format_module_path(false).format_level(true).init();
+	let opt = Opt::from_args();
+	let dir = opt
+		.dir
+		.or_else(|| std::env::var_os(CACHE_DIR_ENV).map(PathBuf::from))
+		.unwrap_or_else(|| PathBuf::from("."));
+
+	let result = match opt.cmd {
+		Command::Snapshot(cmd) => snapshot(dir, cmd).await,
+		#[cfg(feature = "executor")]
+		Command::Call { snapshot, method, data, wasm } => call(snapshot, method, data, wasm).await,
+	};
+	if let Err(why) = result {
+		eprintln!("error: {}", why);
+		std::process::exit(1);
+	}
+}
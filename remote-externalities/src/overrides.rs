@@ -0,0 +1,173 @@
+//! Storage overrides, read from a json or yaml file.
+//!
+//! An overrides file is a list of storage items to set or remove after the state is scraped. Keys
+//! and values are hex encoded, scale encoded, and each key is hashed with the given hasher, e.g.:
+//!
+//! ```yaml
+//! # set a plain value.
+//! - module: Staking
+//!   item: ValidatorCount
+//!   value: "0x64000000"
+//! # set a value of a map.
+//! - module: Staking
+//!   item: Bonded
+//!   keys: [{ hasher: Twox64Concat, key: "0xd43593c715fdd31c61141abd04a99fd6822c8558854ccde39a5684e7a56da27d" }]
+//!   value: "0xd43593c715fdd31c61141abd04a99fd6822c8558854ccde39a5684e7a56da27d"
+//! # remove all the values of a map.
+//! - module: Staking
+//!   item: Nominators
+//! ```
+//!
+//! The same, as a json list of objects, is accepted if the file does not end with `.yaml` or
+//! `.yml`.
+
+use crate::Error;
+use serde::Deserialize;
+use sp_core::Bytes;
+use std::{fs, path::Path};
+use sub_storage::entry::{hash, StorageHasher};
+
+/// A part of a key, and the hasher to apply to it.
+#[derive(Debug, Clone, Eq, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OverrideKey {
+	/// The name of the hasher, as in the metadata, e.g. `Blake2_128Concat`.
+	pub hasher: String,
+	/// The scale encoded key.
+	pub key: Bytes,
+}
+
+/// A single override.
+#[derive(Debug, Clone, Eq, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Override {
+	/// The storage prefix of the module.
+	pub module: String,
+	/// The name of the storage item.
+	pub item: String,
+	/// The keys of a map. Empty for plain storage items.
+	#[serde(default)]
+	pub keys: Vec<OverrideKey>,
+	/// The scale encoded value. If `None`, all the keys that start with the final key of this
+	/// override are removed instead, e.g. all the values of a map if `keys` is empty.
+	pub value: Option<Bytes>,
+}
+
+/// Parse the name of a hasher.
+fn hasher(name: &str) -> Option<StorageHasher> {
+	Some(match name {
+		"Blake2_128" => StorageHasher::Blake2_128,
+		"Blake2_256" => StorageHasher::Blake2_256,
+		"Blake2_128Concat" => StorageHasher::Blake2_128Concat,
+		"Twox128" => StorageHasher::Twox128,
+		"Twox256" => StorageHasher::Twox256,
+		"Twox64Concat" => StorageHasher::Twox64Concat,
+		"Identity" => StorageHasher::Identity,
+		_ => return None,
+	})
+}
+
+impl Override {
+	/// The final key of this override, or the prefix to remove if it has no value.
+	pub fn key(&self) -> Result<Vec<u8>, String> {
+		let mut key = sub_storage::module_prefix_raw(self.module.as_bytes(), self.item.as_bytes());
+		for part in &self.keys {
+			let hasher = hasher(&part.hasher)
+				.ok_or_else(|| format!("unknown hasher {} of {}", part.hasher, self.item))?;
+			key.extend(hash(&hasher, &part.key));
+		}
+		Ok(key)
+	}
+}
+
+/// Read the overrides file at `path`.
+pub fn load(path: &Path) -> Result<Vec<Override>, Error> {
+	let error = |reason: String| Error::Overrides { path: path.to_path_buf(), reason };
+	let content = fs::read_to_string(path).map_err(|e| error(e.to_string()))?;
+	let is_yaml = path.extension().map_or(false, |e| e == "yaml" || e == "yml");
+	let overrides: Vec<Override> = if is_yaml {
+		serde_yaml::from_str(&content).map_err(|e| error(e.to_string()))?
+	} else {
+		serde_json::from_str(&content).map_err(|e| error(e.to_string()))?
+	};
+	// fail early on unknown hashers, rather than when building.
+	for o in &overrides {
+		o.key().map_err(error)?;
+	}
+	Ok(overrides)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{snapshot::tests::test_header, Builder, CacheMode, Snapshot};
+	use sp_core::storage::{StorageData, StorageKey};
+
+	#[tokio::test]
+	async fn overrides_work() {
+		use codec::Encode;
+		let temp = tempfile::tempdir().unwrap();
+		let dir = temp.path();
+		let path = dir.join("state.bin");
+		let count = sub_storage::value_key(b"Staking", b"ValidatorCount");
+		let era = sub_storage::value_key(b"Staking", b"CurrentEra");
+		let bonded = sub_storage::map_key::<frame_support::Twox64Concat>(
+			b"Staking",
+			b"Bonded",
+			&1u64.encode(),
+		);
+		let nominators = sub_storage::map_prefix_key(b"Staking", b"Nominators");
+		let nominator = StorageKey([nominators.0.clone(), vec![1]].concat());
+		let pairs = vec![
+			(count.clone(), StorageData(10u32.encode())),
+			(nominator.clone(), StorageData(vec![1])),
+			(StorageKey([nominators.0.clone(), vec![2]].concat()), StorageData(vec![2])),
+		];
+		Snapshot::write(&path, &test_header(), &pairs).unwrap();
+
+		let yaml = dir.join("overrides.yaml");
+		let content = r#"
+- module: Staking
+  item: CurrentEra
+  value: "0x07000000"
+- module: Staking
+  item: Bonded
+  keys: [{ hasher: Twox64Concat, key: "0x0100000000000000" }]
+"#;
+		std::fs::write(&yaml, content).unwrap();
+
+		Builder::new()
+			.cache_mode(CacheMode::Offline { path: path.clone() })
+			.inject_value("Staking", "ValidatorCount", &20u32)
+			.inject_map::<frame_support::Twox64Concat, _, _>("Staking", "Bonded", &1u64, &[9u8; 32])
+			.remove_prefix(&nominators.0)
+			.inject(&[(nominator.clone(), StorageData(vec![3]))])
+			.overrides_file(&yaml)
+			.build()
+			.await
+			.unwrap()
+			.execute_with(|| {
+				assert_eq!(sp_io::storage::get(&count.0), Some(20u32.encode()));
+				assert_eq!(sp_io::storage::get(&era.0), Some(7u32.encode()));
+				// removals, also those of the overrides file, happen before injections.
+				assert_eq!(sp_io::storage::get(&bonded.0), Some(vec![9; 32]));
+				assert_eq!(sp_io::storage::get(&nominator.0), Some(vec![3]));
+				let other_nominator = [nominators.0.clone(), vec![2]].concat();
+				assert_eq!(sp_io::storage::get(&other_nominator), None);
+			});
+
+		let json = dir.join("overrides.json");
+		let content = r#"[{
+			"module": "Staking",
+			"item": "Bonded",
+			"keys": [{ "hasher": "Sha3", "key": "0x01" }]
+		}]"#;
+		std::fs::write(&json, content).unwrap();
+		let result = Builder::new()
+			.cache_mode(CacheMode::Offline { path })
+			.overrides_file(&json)
+			.build()
+			.await;
+		assert!(matches!(result, Err(Error::Overrides { .. })));
+	}
+}
@@ -0,0 +1,283 @@
+//! Following a chain, block by block, while running some code against its state.
+//!
+//! A [`Sidecar`] keeps the state scraped by a [`Builder`] in [`TestExternalities`], and follows
+//! the finalized blocks of the node. For each new block, it:
+//!
+//! 1. calls the user provided hook inside the externalities, which still hold the state of the
+//!    parent block. The hook would typically call `on_initialize`/`on_finalize` of a pallet, or run
+//!    some assertions.
+//! 2. updates the state to the new block, only downloading the keys that were added, and the
+//!    changes of the others, as reported by `state_queryStorage`.
+//! 3. reports where the locally computed state diverges from the one of the node, for the tracked
+//!    part of the state, and resets that part of the externalities to the state of the node.
+//!
+//! The state is only kept in memory while following the chain. Use [`Sidecar::save`] to write it
+//! to the cache.
+
+use crate::{
+	diff::{Change, StateDiff},
+	export, Builder, CacheMode, CacheName, Error, Hash, Header, KeyPair, Transport,
+	TestExternalities, LOG_TARGET,
+};
+use jsonrpsee_types::jsonrpc::Params;
+use jsonrpsee_ws_client::WsSubscription;
+use log::*;
+use sp_core::storage::{StorageData, StorageKey};
+use std::{collections::BTreeMap, path::PathBuf, sync::Arc, time::Duration};
+use sub_storage::metadata::Metadata;
+
+/// How often the finalized head is polled, if the node cannot be subscribed to.
+const POLL_INTERVAL: Duration = Duration::from_secs(6);
+
+/// The block being processed by a [`Sidecar`].
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct BlockContext {
+	/// The number of the new block.
+	pub number: u32,
+	/// The hash of the new block.
+	pub hash: Hash,
+	/// The hash of the parent block, whose state the externalities hold while the hook runs.
+	pub parent_hash: Hash,
+}
+
+/// The outcome of processing a single block.
+#[derive(Debug, Clone)]
+pub struct BlockReport {
+	/// The block that was processed.
+	pub block: BlockContext,
+	/// The difference between the state computed by the hook (before) and the one of the node
+	/// (after), for the tracked part of the state. Empty if the hook reproduced the block exactly.
+	pub divergence: StateDiff,
+	/// The number of keys in the tracked state of the new block.
+	pub keys: usize,
+}
+
+enum Heads {
+	Subscription(WsSubscription<Header>),
+	Polling,
+}
+
+/// Follows the finalized blocks of a chain, see the module docs.
+pub struct Sidecar<F> {
+	builder: Builder,
+	ext: TestExternalities,
+	/// The tracked state of the node at the last processed block.
+	state: BTreeMap<StorageKey, StorageData>,
+	number: u32,
+	target: u32,
+	heads: Heads,
+	hook: F,
+	metadata: Option<Arc<Metadata>>,
+}
+
+impl<F: FnMut(&BlockContext)> Sidecar<F> {
+	/// Scrape the state described by `builder` at the latest finalized block, and start following
+	/// the chain from there.
+	///
+	/// The state is scraped into the cache of `builder`, and then only updated in memory, see
+	/// [`Sidecar::save`]. Any block set in the builder is ignored.
+	pub async fn new(builder: Builder, hook: F) -> Result<Self, Error> {
+		let mut builder = builder;
+		builder.at = None;
+		builder.cache_config = CacheMode::ForceUpdate;
+		builder.cache_name_config =
+			CacheName::Forced(format!("sidecar,{}.bin", builder.filter_names().join(",")));
+
+		let mut ext = TestExternalities::new_empty();
+		builder.pre_build(&mut ext).await?;
+		let number =
+			builder.header.as_ref().expect("header is initialized in `pre_build`; qed").number;
+		info!(target: LOG_TARGET, "sidecar starting at #{} ({:?})", number, builder.final_at());
+
+		let client = builder.rpc_client();
+		let heads = match client.transport() {
+			Transport::Ws(ws) => Heads::Subscription(
+				ws.subscribe(
+					"chain_subscribeFinalizedHeads",
+					Params::None,
+					"chain_unsubscribeFinalizedHeads",
+				)
+				.await
+				.map_err(|e| Error::rpc(client.uri(), "chain_subscribeFinalizedHeads", e))?,
+			),
+			Transport::Http(_) => Heads::Polling,
+		};
+
+		let mut sidecar = Self {
+			builder,
+			ext,
+			state: Default::default(),
+			number,
+			target: number,
+			heads,
+			hook,
+			metadata: None,
+		};
+		sidecar.state = sidecar.tracked_pairs(&sidecar.ext).into_iter().collect();
+		Ok(sidecar)
+	}
+
+	/// Use the given metadata to group the divergences by storage item.
+	pub fn metadata(mut self, metadata: Arc<Metadata>) -> Self {
+		self.metadata = Some(metadata);
+		self
+	}
+
+	/// The externalities, holding the state of the last processed block.
+	pub fn ext(&mut self) -> &mut TestExternalities {
+		&mut self.ext
+	}
+
+	/// Save the tracked state of the last processed block as the cache of the builder, and return
+	/// its path.
+	pub async fn save(&mut self) -> Result<PathBuf, Error> {
+		let at = self.builder.final_at();
+		self.builder.spec_version = self.builder.rpc_get_spec_version(at).await?;
+		let pairs = self.state.iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+		self.builder.save_cache(&self.builder.add_block_context(pairs))?;
+		Ok(self.builder.cache_path())
+	}
+
+	/// The pairs of `ext` that are part of the scraped state, i.e. without injections.
+	fn tracked_pairs(&self, ext: &TestExternalities) -> Vec<KeyPair> {
+		export::pairs_of(ext).into_iter().filter(|(k, _)| self.builder.is_tracked(k)).collect()
+	}
+
+	/// Wait until a block after `self.number` is finalized.
+	async fn wait_for_head(&mut self) -> Result<(), Error> {
+		while self.target <= self.number {
+			self.target = match &mut self.heads {
+				Heads::Subscription(subscription) => {
+					subscription
+						.next()
+						.await
+						.ok_or_else(|| Error::Rpc {
+							method: "chain_subscribeFinalizedHeads",
+							reason: "subscription closed".into(),
+						})?
+						.number
+				}
+				Heads::Polling => {
+					tokio::time::sleep(POLL_INTERVAL).await;
+					let head = self.builder.rpc_get_head().await?;
+					self.builder.rpc_get_header(head).await?.number
+				}
+			};
+		}
+		Ok(())
+	}
+
+	/// Wait for, and process, the next block.
+	///
+	/// Finalized heads can skip blocks. The skipped blocks are still processed one by one.
+	pub async fn next_block(&mut self) -> Result<BlockReport, Error> {
+		self.wait_for_head().await?;
+		let number = self.number + 1;
+		let hash = self.builder.rpc_get_block_hash(number).await?.ok_or_else(|| Error::Rpc {
+			method: "chain_getBlockHash",
+			reason: format!("finalized block #{} does not exist", number),
+		})?;
+		let block = BlockContext { number, hash, parent_hash: self.builder.final_at() };
+		debug!(target: LOG_TARGET, "sidecar processing {:?}", block);
+
+		let hook = &mut self.hook;
+		self.ext.execute_with(|| hook(&block));
+		let local = self.tracked_pairs(&self.ext);
+
+		let scrape_error = |error| Error::Scrape { at: hash, error: Box::new(error) };
+		let header = self.builder.rpc_get_header(hash).await.map_err(scrape_error)?;
+		self.builder.at = Some(hash);
+		self.builder.state_root =
+			if self.builder.is_full_scrape() { Some(header.state_root) } else { None };
+		self.builder.header = Some(header);
+		let parent = self.state.iter().map(|(k, v)| Ok((k.clone(), v.clone())));
+		let state =
+			self.builder.update_pairs(parent, block.parent_hash).await.map_err(scrape_error)?;
+		self.state = state;
+		if self.builder.block_context {
+			self.builder.context =
+				self.builder.fetch_block_context().await.map_err(scrape_error)?;
+		}
+
+		let keys = self.state.len();
+		let remote = self.state.iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+		let divergence = reconcile(&mut self.ext, local, remote, self.metadata.as_deref());
+		let context = &self.builder.context;
+		self.ext.execute_with(|| context.iter().for_each(|(k, v)| sp_io::storage::set(&k.0, &v.0)));
+		if !divergence.is_empty() {
+			let (added, removed, modified) = divergence.counts();
+			warn!(
+				target: LOG_TARGET,
+				"#{} diverged: {} keys missing locally, {} extra, {} different",
+				number,
+				added,
+				removed,
+				modified,
+			);
+		}
+
+		self.number = number;
+		Ok(BlockReport { block, divergence, keys })
+	}
+
+	/// Process blocks forever, passing the report of each to `on_report`.
+	pub async fn run(mut self, mut on_report: impl FnMut(&BlockReport)) -> Result<(), Error> {
+		loop {
+			let report = self.next_block().await?;
+			on_report(&report);
+		}
+	}
+}
+
+/// Compute where `local`, the tracked pairs of `ext` after the hook ran, diverge from `remote`,
+/// the ones of the node, and update `ext` to the latter.
+fn reconcile(
+	ext: &mut TestExternalities,
+	local: Vec<KeyPair>,
+	remote: Vec<KeyPair>,
+	metadata: Option<&Metadata>,
+) -> StateDiff {
+	let divergence = StateDiff::compute(local, remote, metadata);
+	ext.execute_with(|| {
+		for (key, change) in divergence.changes() {
+			match change {
+				Change::Added(value) | Change::Modified { after: value, .. } => {
+					sp_io::storage::set(&key.0, value)
+				}
+				Change::Removed(_) => sp_io::storage::clear(&key.0),
+			}
+		}
+	});
+	divergence
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn divergence_is_reported_and_reset() {
+		let pair = |k: u8, v: u8| (StorageKey(vec![k]), StorageData(vec![v]));
+		let mut ext = TestExternalities::new_empty();
+		ext.insert(vec![1], vec![1]);
+		ext.insert(vec![2], vec![2]);
+		// the hook modifies 1, removes 2 and adds 3.
+		ext.execute_with(|| {
+			sp_io::storage::set(&[1], &[10]);
+			sp_io::storage::clear(&[2]);
+			sp_io::storage::set(&[3], &[3]);
+		});
+		let local = export::pairs_of(&ext);
+		let remote = vec![pair(1, 1), pair(2, 2), pair(4, 4)];
+
+		let divergence = reconcile(&mut ext, local, remote.clone(), None);
+		// (added, removed, modified), from the local state to the one of the node.
+		assert_eq!(divergence.counts(), (2, 1, 1));
+		let mut pairs = export::pairs_of(&ext);
+		pairs.sort();
+		assert_eq!(pairs, remote);
+
+		let divergence = reconcile(&mut ext, export::pairs_of(&ext), remote, None);
+		assert!(divergence.is_empty());
+	}
+}
@@ -0,0 +1,390 @@
+//! The on-disk format of the cached state, also known as a snapshot.
+//!
+//! A snapshot file starts with [`MAGIC`] and the format [`VERSION`], followed by a
+//! [`SnapshotHeader`] describing where the state came from, and finally the key-value pairs
+//! themselves.
+//!
+//! Since version 2, the pairs are written as a zstd compressed stream of records, each being the
+//! length-prefixed key and value, terminated by an end marker. This allows multi-GB states to be
+//! written and read incrementally, see [`SnapshotWriter`] and [`SnapshotReader`]. Snapshots of
+//! version 1, where the pairs are a single `bincode` encoded vector, can still be read.
+
+use crate::{Hash, KeyPair, TestExternalities};
+use serde::{Deserialize, Serialize};
+use sp_core::storage::{StorageData, StorageKey};
+use std::{
+	fmt,
+	fs::{self, File},
+	io::{self, BufReader, BufWriter, Read, Write},
+	path::Path,
+	time::{SystemTime, UNIX_EPOCH},
+};
+
+/// The magic bytes at the start of each snapshot file.
+pub const MAGIC: &[u8; 8] = b"rext-snp";
+/// The current version of the snapshot format.
+pub const VERSION: u16 = 2;
+/// The oldest version of the snapshot format that can still be read.
+pub const MIN_VERSION: u16 = 1;
+/// The default zstd compression level.
+pub const DEFAULT_COMPRESSION_LEVEL: i32 = 3;
+
+/// Marks the end of the stream of records.
+const END_MARKER: u32 = u32::MAX;
+
+/// Information about the origin of a snapshot.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct SnapshotHeader {
+	/// The name of the chain, as reported by `system_chain`.
+	pub chain: String,
+	/// The hash of the block at which the state was scraped.
+	pub block_hash: Hash,
+	/// The number of the block at which the state was scraped.
+	pub block_number: u32,
+	/// The spec version of the runtime at the scraped block.
+	pub spec_version: u32,
+	/// The state root of the scraped block.
+	pub state_root: Hash,
+	/// The filters used when scraping the state. Empty if the entire state was scraped.
+	pub filter: Vec<String>,
+	/// The creation time of the snapshot, in seconds since the unix epoch.
+	pub created_at: u64,
+}
+
+impl SnapshotHeader {
+	/// The current time, as expected in `created_at`.
+	pub fn now() -> u64 {
+		SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+	}
+}
+
+/// Errors that can happen while reading or writing a snapshot.
+#[derive(Debug)]
+pub enum SnapshotError {
+	/// The underlying file could not be read or written.
+	Io(io::Error),
+	/// The file does not start with [`MAGIC`], thus is not a snapshot. This is also the case for
+	/// caches created before the format was versioned.
+	NotASnapshot,
+	/// The file is a snapshot, but of a different version of the format.
+	UnsupportedVersion(u16),
+	/// The file is a snapshot, but its content could not be decoded.
+	Corrupted(String),
+	/// Two or more snapshots cannot be combined, e.g. since they are of different blocks.
+	Incompatible(String),
+	/// A key or value of the given size is too large to be written in a snapshot.
+	TooLarge(usize),
+}
+
+impl fmt::Display for SnapshotError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::Io(e) => write!(f, "snapshot io error: {}", e),
+			Self::NotASnapshot => write!(f, "file is not a snapshot, or of a legacy format"),
+			Self::UnsupportedVersion(v) => write!(
+				f,
+				"snapshot version {} is not supported (expected {} to {})",
+				v, MIN_VERSION, VERSION
+			),
+			Self::Corrupted(why) => write!(f, "snapshot is corrupted: {}", why),
+			Self::Incompatible(why) => write!(f, "snapshots are incompatible: {}", why),
+			Self::TooLarge(len) => {
+				write!(f, "a key or value of {} bytes is too large for a snapshot", len)
+			}
+		}
+	}
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl From<io::Error> for SnapshotError {
+	fn from(e: io::Error) -> Self {
+		Self::Io(e)
+	}
+}
+
+impl From<bincode::Error> for SnapshotError {
+	fn from(e: bincode::Error) -> Self {
+		Self::Corrupted(e.to_string())
+	}
+}
+
+/// Writes a snapshot incrementally, one pair at a time.
+pub struct SnapshotWriter {
+	encoder: zstd::stream::write::Encoder<BufWriter<File>>,
+	count: usize,
+}
+
+impl SnapshotWriter {
+	/// Create a new snapshot at `path`, with the given header and compression level.
+	pub fn create(path: &Path, header: &SnapshotHeader, level: i32) -> Result<Self, SnapshotError> {
+		if let Some(parent) = path.parent() {
+			fs::create_dir_all(parent)?;
+		}
+		let mut writer = BufWriter::new(File::create(path)?);
+		writer.write_all(MAGIC)?;
+		writer.write_all(&VERSION.to_le_bytes())?;
+		bincode::serialize_into(&mut writer, header)?;
+		let encoder = zstd::stream::write::Encoder::new(writer, level)?;
+		Ok(Self { encoder, count: 0 })
+	}
+
+	/// Write a single key-value pair.
+	pub fn write_pair(&mut self, key: &[u8], value: &[u8]) -> Result<(), SnapshotError> {
+		for data in [key, value].iter() {
+			if data.len() >= END_MARKER as usize {
+				return Err(SnapshotError::TooLarge(data.len()));
+			}
+			self.encoder.write_all(&(data.len() as u32).to_le_bytes())?;
+			self.encoder.write_all(data)?;
+		}
+		self.count += 1;
+		Ok(())
+	}
+
+	/// Finish the snapshot, returning the number of written pairs.
+	pub fn finish(mut self) -> Result<usize, SnapshotError> {
+		self.encoder.write_all(&END_MARKER.to_le_bytes())?;
+		let mut writer = self.encoder.finish()?;
+		writer.flush()?;
+		Ok(self.count)
+	}
+}
+
+enum Payload {
+	/// Version 1: all the pairs, already decoded.
+	Legacy(std::vec::IntoIter<KeyPair>),
+	/// Version 2: a compressed stream of records.
+	Stream(zstd::stream::read::Decoder<BufReader<File>>),
+}
+
+/// Reads a snapshot incrementally, as an iterator of pairs.
+pub struct SnapshotReader {
+	header: SnapshotHeader,
+	payload: Payload,
+	done: bool,
+}
+
+fn read_preamble<R: Read>(reader: &mut R) -> Result<(u16, SnapshotHeader), SnapshotError> {
+	let mut magic = [0u8; 8];
+	reader.read_exact(&mut magic).map_err(|_| SnapshotError::NotASnapshot)?;
+	if &magic != MAGIC {
+		return Err(SnapshotError::NotASnapshot);
+	}
+
+	let mut version = [0u8; 2];
+	reader.read_exact(&mut version)?;
+	let version = u16::from_le_bytes(version);
+	if version < MIN_VERSION || version > VERSION {
+		return Err(SnapshotError::UnsupportedVersion(version));
+	}
+
+	Ok((version, bincode::deserialize_from(reader)?))
+}
+
+pub(crate) fn read_chunk<R: Read>(reader: &mut R) -> Result<Option<Vec<u8>>, SnapshotError> {
+	let mut len = [0u8; 4];
+	reader
+		.read_exact(&mut len)
+		.map_err(|e| SnapshotError::Corrupted(format!("unexpected end of stream: {}", e)))?;
+	let len = u32::from_le_bytes(len);
+	if len == END_MARKER {
+		return Ok(None);
+	}
+	// the length is not trusted, so the buffer only grows as data is actually read.
+	let mut data = Vec::new();
+	reader
+		.take(len as u64)
+		.read_to_end(&mut data)
+		.map_err(|e| SnapshotError::Corrupted(format!("unexpected end of stream: {}", e)))?;
+	if data.len() != len as usize {
+		return Err(SnapshotError::Corrupted(format!(
+			"unexpected end of stream: expected {} bytes, got {}",
+			len,
+			data.len()
+		)));
+	}
+	Ok(Some(data))
+}
+
+impl SnapshotReader {
+	/// Open the snapshot at `path`. Only the header is read at this point.
+	pub fn open(path: &Path) -> Result<Self, SnapshotError> {
+		let mut reader = BufReader::new(File::open(path)?);
+		let (version, header) = read_preamble(&mut reader)?;
+		let payload = if version == 1 {
+			let pairs: Vec<KeyPair> = bincode::deserialize_from(&mut reader)?;
+			Payload::Legacy(pairs.into_iter())
+		} else {
+			Payload::Stream(zstd::stream::read::Decoder::with_buffer(reader)?)
+		};
+		Ok(Self { header, payload, done: false })
+	}
+
+	/// The header of the snapshot.
+	pub fn header(&self) -> &SnapshotHeader {
+		&self.header
+	}
+
+	fn next_pair(&mut self) -> Result<Option<KeyPair>, SnapshotError> {
+		match &mut self.payload {
+			Payload::Legacy(pairs) => Ok(pairs.next()),
+			Payload::Stream(decoder) => match read_chunk(decoder)? {
+				None => Ok(None),
+				Some(key) => {
+					let value = read_chunk(decoder)?.ok_or_else(|| {
+						SnapshotError::Corrupted("end of stream in the middle of a pair".into())
+					})?;
+					Ok(Some((StorageKey(key), StorageData(value))))
+				}
+			},
+		}
+	}
+
+	/// Insert all the remaining pairs directly into `ext`, returning their count.
+	pub fn load_into(self, ext: &mut TestExternalities) -> Result<usize, SnapshotError> {
+		let mut count = 0;
+		for pair in self {
+			let (k, v) = pair?;
+			ext.insert(k.0, v.0);
+			count += 1;
+		}
+		Ok(count)
+	}
+}
+
+impl Iterator for SnapshotReader {
+	type Item = Result<KeyPair, SnapshotError>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		if self.done {
+			return None;
+		}
+		let next = self.next_pair();
+		if !matches!(next, Ok(Some(_))) {
+			self.done = true;
+		}
+		next.transpose()
+	}
+}
+
+/// A header, and the key-value pairs of a scraped state.
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+	/// The header.
+	pub header: SnapshotHeader,
+	/// All the key-value pairs.
+	pub pairs: Vec<KeyPair>,
+}
+
+impl Snapshot {
+	/// Write `self` to the given path.
+	pub fn save(&self, path: &Path) -> Result<(), SnapshotError> {
+		Self::write(path, &self.header, &self.pairs)
+	}
+
+	/// Write a snapshot made of the given header and pairs to the given path.
+	pub fn write(
+		path: &Path,
+		header: &SnapshotHeader,
+		pairs: &[KeyPair],
+	) -> Result<(), SnapshotError> {
+		let mut writer = SnapshotWriter::create(path, header, DEFAULT_COMPRESSION_LEVEL)?;
+		for (k, v) in pairs {
+			writer.write_pair(&k.0, &v.0)?;
+		}
+		writer.finish().map(|_| ())
+	}
+
+	/// Read a snapshot from the given path.
+	///
+	/// This holds all the pairs in memory. Prefer [`SnapshotReader`] for large states.
+	pub fn load(path: &Path) -> Result<Self, SnapshotError> {
+		let reader = SnapshotReader::open(path)?;
+		let header = reader.header().clone();
+		let pairs = reader.collect::<Result<Vec<_>, _>>()?;
+		Ok(Self { header, pairs })
+	}
+
+	/// Read only the header of the snapshot at the given path.
+	pub fn load_header(path: &Path) -> Result<SnapshotHeader, SnapshotError> {
+		let mut reader = BufReader::new(File::open(path)?);
+		read_preamble(&mut reader).map(|(_, header)| header)
+	}
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+	use super::*;
+
+	/// A header for snapshots used in tests.
+	pub(crate) fn test_header() -> SnapshotHeader {
+		SnapshotHeader {
+			chain: "Test".into(),
+			block_hash: Hash::repeat_byte(1),
+			block_number: 42,
+			spec_version: 7,
+			state_root: Hash::repeat_byte(2),
+			filter: vec!["System".into()],
+			created_at: SnapshotHeader::now(),
+		}
+	}
+
+	#[test]
+	fn snapshot_roundtrip_works() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("snapshot-roundtrip.bin");
+		let pairs = vec![
+			(StorageKey(vec![1, 2, 3]), StorageData(vec![4, 5])),
+			(StorageKey(vec![6]), StorageData(vec![])),
+		];
+		Snapshot::write(&path, &test_header(), &pairs).unwrap();
+
+		assert_eq!(Snapshot::load_header(&path).unwrap(), test_header());
+		let loaded = Snapshot::load(&path).unwrap();
+		assert_eq!(loaded.header.block_number, 42);
+		assert_eq!(loaded.pairs, pairs);
+	}
+
+	#[test]
+	fn snapshot_reads_legacy_version() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("snapshot-legacy.bin");
+		let pairs = vec![(StorageKey(vec![1, 2, 3]), StorageData(vec![4, 5]))];
+		let mut legacy = MAGIC.to_vec();
+		legacy.extend(&1u16.to_le_bytes());
+		legacy.extend(bincode::serialize(&test_header()).unwrap());
+		legacy.extend(bincode::serialize(&pairs).unwrap());
+		std::fs::write(&path, legacy).unwrap();
+
+		assert_eq!(Snapshot::load(&path).unwrap().pairs, pairs);
+	}
+
+	#[test]
+	fn snapshot_rejects_bad_files() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("snapshot-bad.bin");
+
+		std::fs::write(&path, bincode::serialize(&vec![(vec![1u8], vec![2u8])]).unwrap()).unwrap();
+		assert!(matches!(Snapshot::load(&path), Err(SnapshotError::NotASnapshot)));
+
+		let mut wrong_version = MAGIC.to_vec();
+		wrong_version.extend(&(VERSION + 1).to_le_bytes());
+		std::fs::write(&path, wrong_version).unwrap();
+		assert!(matches!(Snapshot::load(&path), Err(SnapshotError::UnsupportedVersion(_))));
+
+		let mut truncated = MAGIC.to_vec();
+		truncated.extend(&VERSION.to_le_bytes());
+		truncated.extend(&[1, 2, 3]);
+		std::fs::write(&path, truncated).unwrap();
+		assert!(matches!(Snapshot::load(&path), Err(SnapshotError::Corrupted(_))));
+
+		// a huge length, followed by a few bytes only, is rejected without allocating it upfront.
+		let mut huge = (u32::MAX - 1).to_le_bytes().to_vec();
+		huge.extend(&[1, 2, 3]);
+		assert!(matches!(read_chunk(&mut &huge[..]), Err(SnapshotError::Corrupted(_))));
+		let mut chunk = 3u32.to_le_bytes().to_vec();
+		chunk.extend(&[1, 2, 3]);
+		assert_eq!(read_chunk(&mut &chunk[..]).unwrap(), Some(vec![1, 2, 3]));
+	}
+}
@@ -2,7 +2,11 @@ use ansi_term::{Colour::*, Style};
 use frame_metadata::{RuntimeMetadata, RuntimeMetadataPrefixed, StorageEntryType};
 use separator::Separatable;
 use structopt::StructOpt;
-use sub_storage::{get_head, get_metadata, unwrap_decoded, Hash, StorageKey};
+use sub_storage::{
+	get_head, get_metadata,
+	progress::{Progress, ProgressConfig},
+	unwrap_decoded, Hash, StorageKey,
+};
 
 const KB: usize = 1024;
 const MB: usize = KB * KB;
@@ -127,18 +131,14 @@ struct Opt {
 	#[structopt(long, default_value = "ws://localhost:9944")]
 	uri: String,
 
-	/// If true, intermediate values will be printed.
+	/// If true, intermediate values and a progress bar will be printed.
 	#[structopt(long, short)]
 	progress: bool,
 
-	/// Weather to scrape all pairs or just the size of them.
+	/// Weather to also count the keys of each map, or just scrape their size.
 	///
 	/// If enabled, the command might take longer but then the number of keys in each map is also
-	/// scraped.
-	///
-	/// # Warning
-	///
-	/// This uses an unsafe RPC call and can only be used if the target node allows it.
+	/// scraped. Only the keys are transferred, not the values.
 	#[structopt(long, short)]
 	scrape_pairs: bool,
 }
@@ -169,6 +169,13 @@ async fn main() -> () {
 
 	if let RuntimeMetadata::V12(inner) = metadata {
 		let decode_modules = unwrap_decoded(inner.modules);
+		let total_items = decode_modules
+			.iter()
+			.filter_map(|m| m.storage.clone())
+			.map(|s| unwrap_decoded(unwrap_decoded(s).entries).len())
+			.sum::<usize>();
+		let config = ProgressConfig { bar: opt.progress, ..ProgressConfig::new(LOG_TARGET) };
+		let mut progress = Progress::new(config, "scraping", "items", Some(total_items));
 		for module in decode_modules.into_iter() {
 			let name = unwrap_decoded(module.name);
 
@@ -193,23 +200,16 @@ async fn main() -> () {
 				let key_prefix =
 					sub_storage::module_prefix_raw(prefix.as_bytes(), storage_name.as_bytes());
 
-				let (pairs, size) = if opt.scrape_pairs {
-					// this should be slower but gives more detail.
-					let pairs =
-						sub_storage::get_pairs(StorageKey(key_prefix.clone()), &client, at).await;
-					let pairs = pairs
-						.into_iter()
-						.map(|(k, v)| (k.0, v.0))
-						.collect::<Vec<(Vec<u8>, Vec<u8>)>>();
-					let size = pairs.iter().fold(0, |acc, x| acc + x.1.len());
-					(pairs, size)
-				} else {
-					// This should be faster
-					let size = sub_storage::get_storage_size(StorageKey(key_prefix), &client, at)
+				let size =
+					sub_storage::get_storage_size(StorageKey(key_prefix.clone()), &client, at)
 						.await
 						.unwrap_or_default() as usize;
-					let pairs: Vec<_> = vec![];
-					(pairs, size)
+				let is_map = !matches!(ty, StorageEntryType::Plain(_));
+				let count = if opt.scrape_pairs && is_map {
+					// this should be slower but gives more detail.
+					sub_storage::count_keys(StorageKey(key_prefix), &client, at).await
+				} else {
+					0
 				};
 
 				log::debug!(
@@ -217,15 +217,16 @@ async fn main() -> () {
 					"{:?}::{:?} => count: {}, size: {} bytes",
 					name,
 					storage_name,
-					pairs.len(),
+					count,
 					size
 				);
 
+				progress.record(&name, 1, size);
 				module_info.size += size;
 				let item = match ty {
 					StorageEntryType::Plain(_) => StorageItem::Value(size),
 					StorageEntryType::Map { .. } | StorageEntryType::DoubleMap { .. } => {
-						StorageItem::Map(size, pairs.len())
+						StorageItem::Map(size, count)
 					}
 				};
 				module_info.items.push(Storage::new(storage_name, item));
@@ -238,6 +239,7 @@ async fn main() -> () {
 			}
 			modules.push(module_info);
 		}
+		progress.finish();
 
 		println!("Scraping results done. Final sorted tree:");
 		modules.sort_by_key(|m| m.size);
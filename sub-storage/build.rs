@@ -1,11 +1,194 @@
+//! Build script of `sub-storage`.
+//!
+//! Generates typed storage descriptors from the metadata blob pointed to by the
+//! `SUB_STORAGE_METADATA` environment variable. The blob can either be the raw SCALE encoded
+//! metadata, or its hex representation (as returned by `state_getMetadata`). If the variable is not
+//! set, an empty set of descriptors is generated.
+
+use codec::Decode;
+use frame_metadata::{
+	DecodeDifferent, RuntimeMetadata, RuntimeMetadataPrefixed, StorageEntryType, StorageHasher,
+};
+use std::{env, fmt::Write, fs, path::Path};
+
 #[cfg(feature = "build-docs")]
 use std::process::Command;
 
-#[cfg(not(feature = "build-docs"))]
-fn main() {}
+const METADATA_ENV: &str = "SUB_STORAGE_METADATA";
+
+fn unwrap_decoded<B: Eq + PartialEq + std::fmt::Debug, O: Eq + PartialEq + std::fmt::Debug>(
+	input: DecodeDifferent<B, O>,
+) -> O {
+	if let DecodeDifferent::Decoded(o) = input {
+		o
+	} else {
+		panic!("Data is not decoded: {:?}", input)
+	}
+}
+
+/// Split a comma separated list of types, respecting nested generics and tuples.
+fn split_top_level(input: &str) -> Vec<&str> {
+	let mut parts = vec![];
+	let mut depth = 0;
+	let mut start = 0;
+	for (i, c) in input.char_indices() {
+		match c {
+			'<' | '(' | '[' => depth += 1,
+			'>' | ')' | ']' => depth -= 1,
+			',' if depth == 0 => {
+				parts.push(input[start..i].trim());
+				start = i + 1;
+			}
+			_ => {}
+		}
+	}
+	let last = input[start..].trim();
+	if !last.is_empty() {
+		parts.push(last);
+	}
+	parts
+}
+
+/// Map a type name, as declared in the metadata, to a rust type.
+///
+/// Only types that are common among substrate chains are known. Anything else is `None`, and will
+/// be represented as `Raw`.
+fn rust_type(ty: &str) -> Option<String> {
+	let ty = ty.trim();
+	let known = match ty {
+		"u8" | "u16" | "u32" | "u64" | "u128" | "bool" => ty,
+		"T::AccountId" | "AccountId" => "sp_core::crypto::AccountId32",
+		"T::BlockNumber" | "BlockNumber" | "T::Index" | "EraIndex" | "SessionIndex"
+		| "ReferendumIndex" | "PropIndex" | "ProposalIndex" | "RegistrarIndex" | "MemberCount" => "u32",
+		"BalanceOf<T>" | "BalanceOf<T, I>" | "T::Balance" | "Balance" => "u128",
+		"T::Hash" | "Hash" => "sp_core::H256",
+		"T::Moment" | "Moment" => "u64",
+		_ => "",
+	};
+	if !known.is_empty() {
+		return Some(known.to_string());
+	}
+
+	if let Some(inner) = ty.strip_prefix("Vec<").and_then(|t| t.strip_suffix('>')) {
+		return rust_type(inner).map(|inner| format!("Vec<{}>", inner));
+	}
+	if let Some(inner) = ty.strip_prefix("Option<").and_then(|t| t.strip_suffix('>')) {
+		return rust_type(inner).map(|inner| format!("Option<{}>", inner));
+	}
+	if let Some(inner) = ty.strip_prefix('(').and_then(|t| t.strip_suffix(')')) {
+		if inner.trim().is_empty() {
+			return Some("()".to_string());
+		}
+		let parts =
+			split_top_level(inner).into_iter().map(rust_type).collect::<Option<Vec<_>>>()?;
+		return Some(format!("({},)", parts.join(", ")));
+	}
+
+	None
+}
+
+fn rust_type_or_raw(ty: &str) -> String {
+	rust_type(ty).unwrap_or_else(|| "Raw".to_string())
+}
+
+fn hasher_path(hasher: &StorageHasher) -> String {
+	format!("StorageHasher::{:?}", hasher)
+}
+
+fn read_metadata(path: &Path) -> RuntimeMetadataPrefixed {
+	let bytes = fs::read(path).expect("failed to read the metadata file");
+	let trimmed = String::from_utf8(bytes.clone()).ok().map(|s| s.trim().to_string());
+	let raw = match trimmed.as_deref().and_then(|s| s.strip_prefix("0x")) {
+		Some(hex_str) => hex::decode(hex_str).expect("invalid hex metadata"),
+		None => bytes,
+	};
+	RuntimeMetadataPrefixed::decode(&mut &*raw).expect("Runtime Metadata failed to decode")
+}
+
+fn generate(metadata: RuntimeMetadataPrefixed) -> String {
+	let mut out = String::new();
+	let modules = match metadata.1 {
+		RuntimeMetadata::V12(inner) => unwrap_decoded(inner.modules),
+		_ => panic!("Unsupported metadata version. Please make an issue."),
+	};
+
+	for module in modules {
+		let module_name = unwrap_decoded(module.name);
+		let storage = match module.storage {
+			Some(storage) => unwrap_decoded(storage),
+			None => continue,
+		};
+		let prefix = unwrap_decoded(storage.prefix);
+
+		writeln!(out, "/// Storage items of the `{}` module.", module_name).unwrap();
+		writeln!(out, "#[allow(non_snake_case, non_upper_case_globals, unused_imports)]").unwrap();
+		writeln!(out, "pub mod {} {{", module_name).unwrap();
+		writeln!(out, "\tuse crate::entry::*;").unwrap();
+
+		for entry in unwrap_decoded(storage.entries) {
+			let name = unwrap_decoded(entry.name);
+			let (kind, value, hashers, doc) = match entry.ty {
+				StorageEntryType::Plain(value) => {
+					let value = unwrap_decoded(value);
+					("Plain".to_string(), rust_type_or_raw(&value), vec![], value)
+				}
+				StorageEntryType::Map { hasher, key, value, .. } => {
+					let (key, value) = (unwrap_decoded(key), unwrap_decoded(value));
+					(
+						format!("Map<{}>", rust_type_or_raw(&key)),
+						rust_type_or_raw(&value),
+						vec![hasher],
+						format!("{} => {}", key, value),
+					)
+				}
+				StorageEntryType::DoubleMap { hasher, key1, key2, value, key2_hasher } => {
+					let (key1, key2, value) =
+						(unwrap_decoded(key1), unwrap_decoded(key2), unwrap_decoded(value));
+					(
+						format!(
+							"DoubleMap<{}, {}>",
+							rust_type_or_raw(&key1),
+							rust_type_or_raw(&key2)
+						),
+						rust_type_or_raw(&value),
+						vec![hasher, key2_hasher],
+						format!("({}, {}) => {}", key1, key2, value),
+					)
+				}
+			};
+			let hashers = hashers.iter().map(hasher_path).collect::<Vec<_>>().join(", ");
+
+			writeln!(out, "\t/// `{}::{}`: `{}`.", module_name, name, doc).unwrap();
+			writeln!(
+				out,
+				"\tpub const {}: StorageEntry<{}, {}> = StorageEntry::new({:?}, {:?}, &[{}]);",
+				name, kind, value, prefix, name, hashers,
+			)
+			.unwrap();
+		}
+
+		writeln!(out, "}}").unwrap();
+	}
+
+	out
+}
 
-#[cfg(feature = "build-docs")]
 fn main() {
-	println!("cargo:rerun-if-changed=src/lib.rs");
-	let _ = Command::new("cargo").arg("readme").arg(">").arg("README.md").output().unwrap();
+	println!("cargo:rerun-if-env-changed={}", METADATA_ENV);
+	let out_dir = env::var("OUT_DIR").expect("OUT_DIR is always set by cargo");
+	let generated = match env::var(METADATA_ENV) {
+		Ok(path) => {
+			println!("cargo:rerun-if-changed={}", path);
+			generate(read_metadata(Path::new(&path)))
+		}
+		Err(_) => String::new(),
+	};
+	fs::write(Path::new(&out_dir).join("descriptors.rs"), generated)
+		.expect("failed to write the generated descriptors");
+
+	#[cfg(feature = "build-docs")]
+	{
+		println!("cargo:rerun-if-changed=src/lib.rs");
+		let _ = Command::new("cargo").arg("readme").arg(">").arg("README.md").output().unwrap();
+	}
 }
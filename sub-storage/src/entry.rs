@@ -0,0 +1,161 @@
+//! Typed storage descriptors.
+//!
+//! A [`StorageEntry`] knows the module, name and hashers of a storage item, as well as the types of
+//! its key(s) and value. Reading through a descriptor can thus not use the wrong hasher, or the
+//! wrong key type.
+//!
+//! Descriptors can be written by hand, but they are usually generated from a metadata blob by the
+//! build script of this crate. See [`crate::descriptors`].
+
+use crate::{module_prefix_raw, Client, Hash, StorageKey};
+use codec::{Decode, Encode, Error as CodecError, Input};
+pub use frame_metadata::StorageHasher;
+use frame_support::StorageHasher as _;
+use std::marker::PhantomData;
+
+/// Hash the given data with the given hasher, as declared in the metadata.
+pub fn hash(hasher: &StorageHasher, data: &[u8]) -> Vec<u8> {
+	use frame_support::{
+		Blake2_128, Blake2_128Concat, Blake2_256, Identity, Twox128, Twox256, Twox64Concat,
+	};
+	match hasher {
+		StorageHasher::Blake2_128 => Blake2_128::hash(data).to_vec(),
+		StorageHasher::Blake2_256 => Blake2_256::hash(data).to_vec(),
+		StorageHasher::Blake2_128Concat => Blake2_128Concat::hash(data),
+		StorageHasher::Twox128 => Twox128::hash(data).to_vec(),
+		StorageHasher::Twox256 => Twox256::hash(data).to_vec(),
+		StorageHasher::Twox64Concat => Twox64Concat::hash(data),
+		StorageHasher::Identity => Identity::hash(data),
+	}
+}
+
+/// The kind of a storage item, which determines the type of its key.
+pub trait EntryKind {
+	/// The type of the key that is needed to address a single value.
+	type Key;
+
+	/// Encode each part of the key separately, in the same order as the hashers.
+	fn encode_parts(key: &Self::Key) -> Vec<Vec<u8>>;
+}
+
+/// A storage value, with no keys.
+pub struct Plain;
+
+impl EntryKind for Plain {
+	type Key = ();
+
+	fn encode_parts(_: &Self::Key) -> Vec<Vec<u8>> {
+		vec![]
+	}
+}
+
+/// A storage map with key `K`.
+pub struct Map<K>(PhantomData<K>);
+
+impl<K: Encode> EntryKind for Map<K> {
+	type Key = K;
+
+	fn encode_parts(key: &Self::Key) -> Vec<Vec<u8>> {
+		vec![key.encode()]
+	}
+}
+
+/// A storage double map with keys `K1` and `K2`.
+pub struct DoubleMap<K1, K2>(PhantomData<(K1, K2)>);
+
+impl<K1: Encode, K2: Encode> EntryKind for DoubleMap<K1, K2> {
+	type Key = (K1, K2);
+
+	fn encode_parts(key: &Self::Key) -> Vec<Vec<u8>> {
+		vec![key.0.encode(), key.1.encode()]
+	}
+}
+
+/// A type that could not be resolved from the metadata.
+///
+/// It encodes as-is, and decodes all of the remaining input. This still allows an item to be
+/// addressed with the correct hashers, while the caller takes care of the encoding.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Raw(pub Vec<u8>);
+
+impl Encode for Raw {
+	fn using_encoded<R, F: FnOnce(&[u8]) -> R>(&self, f: F) -> R {
+		f(&self.0)
+	}
+}
+
+impl Decode for Raw {
+	fn decode<I: Input>(input: &mut I) -> Result<Self, CodecError> {
+		match input.remaining_len()? {
+			Some(len) => {
+				let mut buffer = vec![0u8; len];
+				input.read(&mut buffer)?;
+				Ok(Raw(buffer))
+			}
+			None => {
+				let mut buffer = vec![];
+				while let Ok(byte) = input.read_byte() {
+					buffer.push(byte);
+				}
+				Ok(Raw(buffer))
+			}
+		}
+	}
+}
+
+/// A typed descriptor of a storage item.
+///
+/// `K` is the [`EntryKind`] of the item, and `V` the type of its value.
+pub struct StorageEntry<K, V> {
+	/// The storage prefix of the module.
+	pub module: &'static str,
+	/// The name of the storage item.
+	pub name: &'static str,
+	/// The hashers of the keys, one per key.
+	pub hashers: &'static [StorageHasher],
+	_marker: PhantomData<(K, V)>,
+}
+
+impl<K, V> StorageEntry<K, V> {
+	/// Create a new descriptor.
+	///
+	/// The number of hashers must match the number of keys of `K`.
+	pub const fn new(
+		module: &'static str,
+		name: &'static str,
+		hashers: &'static [StorageHasher],
+	) -> Self {
+		Self { module, name, hashers, _marker: PhantomData }
+	}
+
+	/// The prefix of all the keys of this item.
+	pub fn prefix(&self) -> StorageKey {
+		StorageKey(module_prefix_raw(self.module.as_bytes(), self.name.as_bytes()))
+	}
+}
+
+impl<K: EntryKind, V: Decode> StorageEntry<K, V> {
+	/// The final storage key of the value stored under `key`.
+	pub fn key(&self, key: &K::Key) -> StorageKey {
+		let parts = K::encode_parts(key);
+		assert_eq!(parts.len(), self.hashers.len(), "number of hashers and keys must match");
+		let mut final_key = self.prefix().0;
+		for (hasher, part) in self.hashers.iter().zip(parts.iter()) {
+			final_key.extend(hash(hasher, part));
+		}
+		StorageKey(final_key)
+	}
+
+	/// Read the value stored under `key`.
+	pub async fn get(&self, client: &Client, key: &K::Key, at: Hash) -> Option<V> {
+		crate::read::<V>(self.key(key), client, at).await
+	}
+}
+
+impl<K, V> Clone for StorageEntry<K, V> {
+	fn clone(&self) -> Self {
+		Self::new(self.module, self.name, self.hashers)
+	}
+}
+
+impl<K, V> Copy for StorageEntry<K, V> {}
@@ -1,12 +1,12 @@
 //! Some helper functions for common substrate chains.
 
-use crate::{Hash, Client};
+use crate::{Hash, Client, StorageKey};
 use ansi_term::Colour;
 use codec::{Decode, Encode};
 use frame_support::{Blake2_128Concat, Twox64Concat};
 use frame_system::AccountInfo;
 use pallet_balances::AccountData;
-use std::fmt::Debug;
+use std::{collections::BTreeMap, fmt::Debug, fs, path::Path};
 
 /// Get the nick of a given account id.
 pub async fn get_nick<Balance: Decode>(who: &[u8], client: &Client, at: Hash) -> String {
@@ -95,3 +95,291 @@ pub async fn get_account_data_at<Balance: Decode, Nonce: Decode>(
 	.await
 	.unwrap()
 }
+
+/// The block number type of the chains supported by the era index.
+pub type BlockNumber = u32;
+
+/// Minimal version of `pallet_staking::ActiveEraInfo`.
+#[derive(Decode)]
+struct ActiveEraInfo {
+	index: u32,
+	#[allow(dead_code)]
+	start: Option<u64>,
+}
+
+/// A header of which we only care about the number.
+#[derive(serde::Deserialize)]
+struct NumberedHeader {
+	number: sp_core::U256,
+}
+
+/// The boundaries of a single era.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Encode, Decode)]
+pub struct EraBoundary {
+	/// The first block at which this era was active.
+	pub first_block: BlockNumber,
+	/// The last block at which this era was active. For the ongoing era, this is the last block
+	/// that was indexed.
+	pub last_block: BlockNumber,
+	/// The first block at which this era was planned, i.e. the election of this era was done.
+	pub planned_block: BlockNumber,
+	/// The session index at `first_block`.
+	pub first_session: u32,
+	/// The session index at `last_block`.
+	pub last_session: u32,
+}
+
+/// An index of the boundaries of each era, built by bisecting `Staking::ActiveEra`,
+/// `Staking::CurrentEra` and `Session::CurrentIndex`.
+///
+/// Building the index for many eras needs a lot of RPC calls, so it can be saved to and loaded from
+/// disk, and later updated with the new eras.
+#[derive(Debug, Clone, Default, Encode, Decode)]
+pub struct EraBoundaryIndex {
+	eras: BTreeMap<u32, EraBoundary>,
+}
+
+async fn read_at_number<T: Decode>(
+	key: StorageKey,
+	number: BlockNumber,
+	client: &Client,
+) -> Option<T> {
+	let at = crate::get_block_hash(client, number)
+		.await
+		.unwrap_or_else(|| panic!("Block {} does not exist", number));
+	crate::read::<T>(key, client, at).await
+}
+
+async fn active_era_at(number: BlockNumber, client: &Client) -> u32 {
+	read_at_number::<ActiveEraInfo>(crate::value_key(b"Staking", b"ActiveEra"), number, client)
+		.await
+		.map_or(0, |info| info.index)
+}
+
+async fn current_era_at(number: BlockNumber, client: &Client) -> u32 {
+	read_at_number::<u32>(crate::value_key(b"Staking", b"CurrentEra"), number, client)
+		.await
+		.unwrap_or_default()
+}
+
+async fn session_at(number: BlockNumber, client: &Client) -> u32 {
+	read_at_number::<u32>(crate::value_key(b"Session", b"CurrentIndex"), number, client)
+		.await
+		.unwrap_or_default()
+}
+
+/// Find the first block in `lo..=hi` at which the era returned by `era_at` is at least `era`, if
+/// any. `era_at` must be monotonically increasing.
+async fn bisect_era<F, Fut>(
+	era: u32,
+	mut lo: BlockNumber,
+	mut hi: BlockNumber,
+	era_at: F,
+) -> Option<BlockNumber>
+where
+	F: Fn(BlockNumber) -> Fut,
+	Fut: std::future::Future<Output = u32>,
+{
+	if era_at(hi).await < era {
+		return None;
+	}
+	while lo < hi {
+		let mid = lo + (hi - lo) / 2;
+		if era_at(mid).await >= era {
+			hi = mid;
+		} else {
+			lo = mid + 1;
+		}
+	}
+	Some(lo)
+}
+
+impl EraBoundaryIndex {
+	/// Load an index that was previously saved to `path`.
+	pub fn load(path: &Path) -> Option<Self> {
+		fs::read(path).ok().and_then(|bytes| Self::decode(&mut &*bytes).ok())
+	}
+
+	/// Save this index to `path`.
+	pub fn save(&self, path: &Path) -> std::io::Result<()> {
+		fs::write(path, self.encode())
+	}
+
+	/// Load the index at `path` if it exists, update it up until the latest finalized block, and
+	/// save it back.
+	pub async fn load_and_update(path: &Path, from_era: u32, client: &Client) -> Self {
+		let mut index = Self::load(path).unwrap_or_default();
+		index.update(from_era, client).await;
+		if let Err(why) = index.save(path) {
+			log::warn!(target: crate::LOG_TARGET, "failed to save era index: {:?}", why);
+		}
+		index
+	}
+
+	/// Index all the eras starting from `from_era` (or the last indexed era, if greater) until
+	/// the latest finalized block.
+	pub async fn update(&mut self, from_era: u32, client: &Client) {
+		let head = crate::get_head(client).await;
+		let head_number = crate::get_header::<NumberedHeader>(client, head)
+			.await
+			.expect("head header must exist")
+			.number
+			.low_u32();
+
+		// the last indexed era might have been ongoing, re-index it.
+		let from_era = match self.eras.iter().next_back() {
+			Some((last, _)) => from_era.max(*last),
+			None => from_era,
+		};
+		let mut lo = self.eras.get(&from_era).map_or(1, |b| b.first_block);
+		let mut era = from_era;
+
+		let mut maybe_first = bisect_era(era, lo, head_number, |n| active_era_at(n, client)).await;
+		while let Some(first_block) = maybe_first {
+			let next_first =
+				bisect_era(era + 1, first_block, head_number, |n| active_era_at(n, client)).await;
+			let last_block = next_first.map_or(head_number, |n| n - 1);
+			let planned_block = bisect_era(era, lo, first_block, |n| current_era_at(n, client))
+				.await
+				.unwrap_or(first_block);
+			let boundary = EraBoundary {
+				first_block,
+				last_block,
+				planned_block,
+				first_session: session_at(first_block, client).await,
+				last_session: session_at(last_block, client).await,
+			};
+			log::debug!(target: crate::LOG_TARGET, "indexed era {}: {:?}", era, boundary);
+			self.eras.insert(era, boundary);
+
+			lo = first_block;
+			era += 1;
+			maybe_first = next_first;
+		}
+	}
+
+	/// The boundaries of the given era, if indexed.
+	pub fn era(&self, era: u32) -> Option<&EraBoundary> {
+		self.eras.get(&era)
+	}
+
+	/// Iterate over all indexed eras.
+	pub fn eras(&self) -> impl Iterator<Item = (&u32, &EraBoundary)> {
+		self.eras.iter()
+	}
+
+	/// The first block of the given era, if indexed.
+	pub fn first_block(&self, era: u32) -> Option<BlockNumber> {
+		self.era(era).map(|b| b.first_block)
+	}
+
+	/// The last block of the given era, if indexed.
+	pub fn last_block(&self, era: u32) -> Option<BlockNumber> {
+		self.era(era).map(|b| b.last_block)
+	}
+
+	/// The last block at which the election window for the given era was open, if indexed.
+	///
+	/// This is the last block before the era was planned.
+	pub fn last_election_block(&self, era: u32) -> Option<BlockNumber> {
+		self.era(era).map(|b| b.planned_block.saturating_sub(1))
+	}
+
+	/// The era that was active at the given block, if indexed.
+	pub fn era_of_block(&self, number: BlockNumber) -> Option<u32> {
+		self.eras
+			.iter()
+			.find(|(_, b)| b.first_block <= number && number <= b.last_block)
+			.map(|(era, _)| *era)
+	}
+
+	/// The last block of each indexed era, for example to be used with [`crate::sample`].
+	pub fn last_blocks(&self) -> Vec<BlockNumber> {
+		self.eras.values().map(|b| b.last_block).collect()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use async_std::task::block_on;
+
+	/// Bisect `era` in `lo..=hi`, on a chain where era `i` starts at block `starts[i]`.
+	fn bisect(starts: &[BlockNumber], era: u32, lo: BlockNumber, hi: BlockNumber) -> Option<u32> {
+		let era_at = |n: BlockNumber| {
+			let era = starts.iter().filter(|start| **start <= n).count().saturating_sub(1) as u32;
+			async move { era }
+		};
+		block_on(bisect_era(era, lo, hi, era_at))
+	}
+
+	fn boundary(first_block: BlockNumber, last_block: BlockNumber) -> EraBoundary {
+		EraBoundary {
+			first_block,
+			last_block,
+			planned_block: first_block - 5,
+			first_session: first_block / 10,
+			last_session: last_block / 10,
+		}
+	}
+
+	fn test_index() -> EraBoundaryIndex {
+		let eras = vec![(3, boundary(10, 19)), (4, boundary(20, 29)), (5, boundary(30, 35))];
+		EraBoundaryIndex { eras: eras.into_iter().collect() }
+	}
+
+	#[test]
+	fn bisect_era_works() {
+		let starts = [0, 10, 20, 30];
+		// boundary at the first block of the range.
+		assert_eq!(bisect(&starts, 1, 10, 40), Some(10));
+		// boundary at the last block of the range.
+		assert_eq!(bisect(&starts, 3, 21, 30), Some(30));
+		// boundary in the middle of the range.
+		assert_eq!(bisect(&starts, 2, 1, 40), Some(20));
+		// already active before the range.
+		assert_eq!(bisect(&starts, 1, 15, 40), Some(15));
+		// no boundary in the range.
+		assert_eq!(bisect(&starts, 4, 1, 40), None);
+		assert_eq!(bisect(&starts, 3, 1, 29), None);
+	}
+
+	#[test]
+	fn era_index_lookup_works() {
+		let index = test_index();
+		assert_eq!(index.first_block(4), Some(20));
+		assert_eq!(index.last_block(4), Some(29));
+		assert_eq!(index.last_election_block(4), Some(14));
+		assert_eq!(index.era_of_block(20), Some(4));
+		assert_eq!(index.era_of_block(35), Some(5));
+		assert_eq!(index.last_blocks(), vec![19, 29, 35]);
+
+		// misses.
+		assert_eq!(index.era(2), None);
+		assert_eq!(index.last_block(6), None);
+		assert_eq!(index.era_of_block(9), None);
+		assert_eq!(index.era_of_block(36), None);
+	}
+
+	#[test]
+	fn era_index_save_load_works() {
+		let path = std::env::temp_dir().join("sub-storage-era-index.bin");
+		let index = test_index();
+		index.save(&path).unwrap();
+
+		let loaded = EraBoundaryIndex::load(&path).unwrap();
+		assert_eq!(loaded.eras, index.eras);
+		std::fs::remove_file(&path).unwrap();
+
+		assert!(EraBoundaryIndex::load(&path).is_none());
+	}
+
+	#[test]
+	fn era_index_drives_sampling() {
+		let blocks = crate::Blocks::Eras { index: test_index(), from: 3, to: 5, step: 2 };
+		assert_eq!(blocks.numbers(), Ok(vec![19, 35]));
+
+		let blocks = crate::Blocks::Eras { index: test_index(), from: 3, to: 6, step: 1 };
+		assert_eq!(blocks.numbers(), Err(crate::SampleError::EraNotIndexed(6)));
+	}
+}
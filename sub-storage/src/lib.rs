@@ -16,10 +16,14 @@
 //! nodes. Namely, [`get_pairs`] and [`enumerate_map`] can only be used against nodes that such
 //! external RPCs.
 //!
+//! If only the keys are needed, [`keys`] and [`count_keys`] use the safe `state_getKeysPaged`
+//! instead.
+//!
 //! THIS IS A TEST.
 
 use codec::Decode;
 use frame_support::StorageHasher;
+use futures::{stream, Stream, StreamExt};
 use sp_core::hashing::twox_128;
 use std::fmt::Debug;
 
@@ -27,9 +31,37 @@ use jsonrpsee_http_client::{HttpClient, HttpConfig};
 use jsonrpsee_ws_client::{WsClient, WsConfig};
 use jsonrpsee_types::jsonrpc::{Params, to_value as to_json_value};
 
+/// Typed storage descriptors.
+pub mod entry;
 /// Helper's module.
 #[cfg(feature = "helpers")]
 pub mod helpers;
+/// Cached runtime metadata.
+pub mod metadata;
+/// Progress and throughput reporting.
+pub mod progress;
+/// Sampling storage over many blocks.
+pub mod series;
+
+pub use series::{sample, Blocks, SampleConfig, SampleError, SamplePoint};
+
+/// Storage descriptors generated from metadata at build time.
+///
+/// Build this crate with `SUB_STORAGE_METADATA=path/to/metadata` to generate a module per pallet,
+/// with a [`entry::StorageEntry`] per storage item, using the correct hashers. The path can point
+/// to either the raw SCALE encoded metadata, or its hex representation. Then:
+///
+/// ```ignore
+/// use sub_storage::descriptors::Staking;
+/// let ledger = Staking::Ledger.get(&client, &controller, at).await;
+/// ```
+///
+/// Types that are not known to the generator are represented as [`entry::Raw`].
+pub mod descriptors {
+	include!(concat!(env!("OUT_DIR"), "/descriptors.rs"));
+}
+
+pub(crate) const LOG_TARGET: &'static str = "sub-storage";
 
 /// re-export some stuff from sp-core.
 pub use sp_core::storage::{StorageData, StorageKey};
@@ -109,6 +141,23 @@ pub async fn read<T: Decode>(key: StorageKey, client: &Client, at: Hash) -> Opti
 	<T as Decode>::decode(&mut encoded.as_slice()).ok()
 }
 
+/// Read the values of all the given keys at once.
+///
+/// The returned values are in the same order as `keys`, and `None` if the key does not exist.
+pub async fn query_storage_at(
+	keys: Vec<StorageKey>,
+	client: &Client,
+	at: Hash,
+) -> Vec<(StorageKey, Option<StorageData>)> {
+	let serialized_keys = to_json_value(keys).expect("StorageKey serialization infallible");
+	let at = to_json_value(at).expect("Block hash serialization infallible");
+	let change_sets: Vec<sp_core::storage::StorageChangeSet<Hash>> = client
+		.request("state_queryStorageAt", Params::Array(vec![serialized_keys, at]))
+		.await
+		.expect("Storage state_queryStorageAt failed");
+	change_sets.into_iter().flat_map(|set| set.changes).collect()
+}
+
 /// Get all storage pairs located under a certain prefix.
 ///
 /// ## Warning
@@ -141,6 +190,61 @@ pub async fn get_pairs_http(
 	jsonrpsee_types::jsonrpc::from_value(json_value).unwrap()
 }
 
+/// The default number of keys requested per page by [`keys`] and [`count_keys`].
+pub const DEFAULT_PAGE_SIZE: u32 = 1000;
+
+/// Get a single page of at most `count` keys located under a certain prefix, starting after
+/// `start_key`, if given.
+///
+/// Unlike [`get_pairs`], this is a safe RPC call, and no values are transferred.
+pub async fn get_keys_paged(
+	prefix: &StorageKey,
+	count: u32,
+	start_key: Option<&StorageKey>,
+	client: &Client,
+	at: Hash,
+) -> Vec<StorageKey> {
+	let serialized_prefix = to_json_value(prefix).expect("StorageKey serialization infallible");
+	let count = to_json_value(count).expect("u32 serialization infallible");
+	let start_key = to_json_value(start_key).expect("StorageKey serialization infallible");
+	let at = to_json_value(at).expect("Block hash serialization infallible");
+	client
+		.request("state_getKeysPaged", Params::Array(vec![serialized_prefix, count, start_key, at]))
+		.await
+		.expect("Storage state_getKeysPaged failed")
+}
+
+/// Stream all the keys located under a certain prefix, without their values.
+///
+/// Keys are fetched lazily, [`DEFAULT_PAGE_SIZE`] at a time, as the stream is consumed.
+pub fn keys<'a>(
+	prefix: StorageKey,
+	client: &'a Client,
+	at: Hash,
+) -> impl Stream<Item = StorageKey> + 'a {
+	stream::unfold((None::<StorageKey>, false), move |(last, done)| {
+		let prefix = prefix.clone();
+		async move {
+			if done {
+				return None;
+			}
+			let page = get_keys_paged(&prefix, DEFAULT_PAGE_SIZE, last.as_ref(), client, at).await;
+			if page.is_empty() {
+				return None;
+			}
+			let done = page.len() < DEFAULT_PAGE_SIZE as usize;
+			let last = page.last().cloned();
+			Some((stream::iter(page), (last, done)))
+		}
+	})
+	.flatten()
+}
+
+/// Count all the keys located under a certain prefix, without transferring any values.
+pub async fn count_keys(prefix: StorageKey, client: &Client, at: Hash) -> usize {
+	keys(prefix, client, at).fold(0, |acc, _| async move { acc + 1 }).await
+}
+
 /// Enumerate all keys and values in a storage map.
 ///
 /// It is basically a wrapper around `get_pairs` that also decodes types.
@@ -187,39 +291,16 @@ pub fn unwrap_decoded<B: Eq + PartialEq + std::fmt::Debug, O: Eq + PartialEq + s
 }
 
 /// Get the constant value stored in metadata of a module.
+///
+/// The metadata is decoded only once per runtime version, see [`metadata::get`].
 pub async fn get_const<T: Decode>(
 	client: &Client,
 	module: &str,
 	name: &str,
 	at: Hash,
 ) -> Option<T> {
-	use frame_metadata::{RuntimeMetadata, RuntimeMetadataPrefixed};
-	let raw_metadata = get_metadata(client, at).await.0;
-	let prefixed_metadata = <RuntimeMetadataPrefixed as codec::Decode>::decode(&mut &*raw_metadata)
-		.expect("Runtime Metadata failed to decode");
-	let metadata = prefixed_metadata.1;
-
-	if let RuntimeMetadata::V12(inner) = metadata {
-		let decode_modules = unwrap_decoded(inner.modules);
-		for module_encoded in decode_modules.into_iter() {
-			let mod_name = unwrap_decoded(module_encoded.name);
-			if mod_name == module {
-				let consts = unwrap_decoded(module_encoded.constants);
-
-				for c in consts {
-					let cname = unwrap_decoded(c.name);
-					let cvalue = unwrap_decoded(c.value);
-					if name == cname {
-						return Decode::decode(&mut &*cvalue).ok();
-					}
-				}
-			}
-		}
-	} else {
-		panic!("Unsupported metadata version. Please make an issue.")
-	}
-
-	None
+	let metadata = metadata::get(client, at).await;
+	metadata.constant(module, name).and_then(|raw| Decode::decode(&mut &*raw).ok())
 }
 
 /// Get the latest finalized head of the chain.
@@ -247,6 +328,15 @@ pub async fn get_header<H: serde::de::DeserializeOwned>(client: &Client, at: Has
 		.expect("get chain header request failed")
 }
 
+/// Get the hash of the block with the given number, if it exists.
+pub async fn get_block_hash(client: &Client, number: u32) -> Option<Hash> {
+	let number = to_json_value(number).expect("Block number serialization infallible");
+	client
+		.request("chain_getBlockHash", Params::Array(vec![number]))
+		.await
+		.expect("get chain block hash request failed")
+}
+
 /// Get the block at the the given hash.
 pub async fn get_block<B: serde::de::DeserializeOwned>(client: &Client, at: Hash) -> Option<B> {
 	let at = to_json_value(at).expect("Block hash serialization infallible");
@@ -406,6 +496,82 @@ mod tests {
 			.is_none());
 	}
 
+	#[test]
+	fn storage_entry_key_matches_manual_key() {
+		use entry::{Map, StorageEntry, StorageHasher};
+		type AccountId = sp_runtime::AccountId32;
+		const ACCOUNT_ENTRY: StorageEntry<
+			Map<AccountId>,
+			AccountInfo<Nonce, AccountData<Balance>>,
+		> = StorageEntry::new("System", "Account", &[StorageHasher::Blake2_128Concat]);
+
+		let account = AccountId::new([1u8; 32]);
+		assert_eq!(
+			ACCOUNT_ENTRY.key(&account),
+			map_key::<frame_support::Blake2_128Concat>(b"System", b"Account", account.as_ref()),
+		);
+		assert_eq!(ACCOUNT_ENTRY.prefix(), map_prefix_key(b"System", b"Account"));
+	}
+
+	#[test]
+	fn sample_works() {
+		type Header = sp_runtime::generic::Header<u32, sp_runtime::traits::BlakeTwo256>;
+		let client = block_on(test_client());
+		let at = block_on(get_head(&client));
+		let head = block_on(get_header::<Header>(&client, at)).unwrap();
+		let key = value_key(b"Balances", b"TotalIssuance");
+		let blocks = Blocks::Stride { from: head.number - 20, to: head.number, step: 10 };
+
+		let points =
+			block_on(sample::<Balance>(&[key.clone()], blocks, &client, &Default::default()))
+				.unwrap();
+		assert_eq!(
+			points.iter().map(|p| p.number).collect::<Vec<_>>(),
+			vec![head.number - 20, head.number - 10, head.number]
+		);
+		assert!(points.iter().all(|p| p.values[0].is_some()));
+
+		let past_head = Blocks::Numbers(vec![head.number, head.number + 1000]);
+		assert_eq!(
+			block_on(sample::<Balance>(&[key], past_head, &client, &Default::default()))
+				.unwrap_err(),
+			SampleError::BlockNotFound(head.number + 1000)
+		);
+	}
+
+	#[test]
+	fn blocks_numbers_works() {
+		assert_eq!(Blocks::Numbers(vec![3, 1]).numbers(), Ok(vec![3, 1]));
+		assert_eq!(Blocks::Stride { from: 10, to: 35, step: 10 }.numbers(), Ok(vec![10, 20, 30]));
+		assert_eq!(
+			Blocks::Stride { from: 10, to: 35, step: 0 }.numbers(),
+			Err(SampleError::ZeroStep)
+		);
+	}
+
+	#[test]
+	fn metadata_is_cached() {
+		let client = block_on(test_client());
+		let at = block_on(get_head(&client));
+
+		let first = block_on(metadata::get(&client, at));
+		let second = block_on(metadata::get(&client, at));
+		assert!(std::sync::Arc::ptr_eq(&first, &second));
+		assert!(first.storage("System", "Account").is_some());
+	}
+
+	#[test]
+	fn count_keys_works() {
+		let client = block_on(test_client());
+		let at = block_on(get_head(&client));
+		let prefix = map_prefix_key(b"Staking", b"Validators");
+
+		let count = block_on(count_keys(prefix.clone(), &client, at));
+		let pairs = block_on(get_pairs(prefix, &client, at));
+		assert!(count > 0);
+		assert_eq!(count, pairs.len());
+	}
+
 	#[tokio::test]
 	async fn can_get_all_storage_http() {
 		let client = create_http_client("http://localhost:9933".into()).await;
@@ -419,4 +585,38 @@ mod tests {
 	fn can_get_all_storage_ws() {
 		todo!()
 	}
+
+	#[test]
+	fn progress_works() {
+		use progress::{Progress, ProgressConfig, ProgressReport, Throughput};
+		use std::sync::{Arc, Mutex};
+
+		let reports = Arc::new(Mutex::new(Vec::<ProgressReport>::new()));
+		let callback_reports = reports.clone();
+		let config = ProgressConfig {
+			interval: std::time::Duration::from_secs(0),
+			callback: Some(Arc::new(move |r: &ProgressReport| {
+				callback_reports.lock().unwrap().push(r.clone())
+			})),
+			..ProgressConfig::new(LOG_TARGET)
+		};
+
+		let mut progress = Progress::new(config, "testing", "keys", Some(4));
+		progress.record("Staking", 1, 10);
+		progress.record("System", 2, 5);
+		progress.record("Staking", 1, 20);
+		let report = progress.finish();
+
+		assert!(report.finished);
+		assert_eq!(report.done, Throughput { items: 4, bytes: 35 });
+		assert_eq!(report.fraction(), Some(1.0));
+		assert_eq!(report.modules["Staking"], Throughput { items: 2, bytes: 30 });
+		assert_eq!(report.modules["System"], Throughput { items: 2, bytes: 5 });
+
+		// a periodic report for each record, and the final one.
+		let reports = reports.lock().unwrap();
+		assert_eq!(reports.len(), 4);
+		assert_eq!(reports[0].done, Throughput { items: 1, bytes: 10 });
+		assert!(!reports[0].finished && reports[3].finished);
+	}
 }
@@ -0,0 +1,217 @@
+//! Decoded runtime metadata, cached per runtime version.
+//!
+//! Downloading and decoding the metadata of a runtime is expensive, and it only ever changes with
+//! a runtime upgrade. Hence, the decoded [`Metadata`] is kept in an in-memory cache keyed by
+//! `(spec_name, spec_version)`. Optionally, the raw metadata can also be persisted to disk via
+//! [`set_cache_dir`], so that subsequent runs of the same tool don't download it again.
+
+use crate::{module_prefix_raw, unwrap_decoded, Client, Hash};
+use frame_metadata::{
+	RuntimeMetadata, RuntimeMetadataPrefixed, StorageEntryModifier, StorageEntryType, StorageHasher,
+};
+use std::{
+	collections::BTreeMap,
+	fs,
+	path::{Path, PathBuf},
+	sync::{Arc, Mutex},
+};
+
+lazy_static::lazy_static! {
+	/// The in-memory cache of all the metadata that has been decoded so far.
+	static ref CACHE: Mutex<BTreeMap<(String, u32), Arc<Metadata>>> = Mutex::new(BTreeMap::new());
+	/// The directory in which raw metadata is persisted, if any.
+	static ref CACHE_DIR: Mutex<Option<PathBuf>> = Mutex::new(None);
+}
+
+/// The layout of a storage item, as declared in the metadata.
+///
+/// Types are only known by their name, as declared in the runtime (e.g. `T::AccountId`).
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum StorageLayout {
+	/// A single value.
+	Plain { value: String },
+	/// A map with one key.
+	Map { hasher: StorageHasher, key: String, value: String },
+	/// A map with two keys.
+	DoubleMap {
+		hasher1: StorageHasher,
+		key1: String,
+		hasher2: StorageHasher,
+		key2: String,
+		value: String,
+	},
+}
+
+/// Information about a single storage item.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct StorageInfo {
+	/// The storage prefix of the module, as used in the key.
+	pub module_prefix: String,
+	/// The name of the storage item.
+	pub name: String,
+	/// The final 32 bytes prefix of all the keys of this item.
+	pub prefix: Vec<u8>,
+	/// The layout of this item.
+	pub layout: StorageLayout,
+	/// If the item is optional, or falls back to a default.
+	pub optional: bool,
+	/// The encoded default value.
+	pub default: Vec<u8>,
+}
+
+/// The decoded metadata of a particular runtime version.
+#[derive(Debug)]
+pub struct Metadata {
+	/// The spec name of the runtime.
+	pub spec_name: String,
+	/// The spec version of the runtime.
+	pub spec_version: u32,
+	/// The raw, encoded, metadata.
+	pub raw: Vec<u8>,
+	constants: BTreeMap<(String, String), Vec<u8>>,
+	storage: BTreeMap<(String, String), StorageInfo>,
+}
+
+impl Metadata {
+	/// Decode the given raw metadata.
+	///
+	/// Panics if the metadata is not decodable, or of an unsupported version.
+	pub fn decode(spec_name: String, spec_version: u32, raw: Vec<u8>) -> Self {
+		let prefixed_metadata = <RuntimeMetadataPrefixed as codec::Decode>::decode(&mut &*raw)
+			.expect("Runtime Metadata failed to decode");
+
+		let mut constants = BTreeMap::new();
+		let mut storage = BTreeMap::new();
+		if let RuntimeMetadata::V12(inner) = prefixed_metadata.1 {
+			for module in unwrap_decoded(inner.modules).into_iter() {
+				let module_name = unwrap_decoded(module.name);
+
+				for c in unwrap_decoded(module.constants) {
+					constants.insert(
+						(module_name.clone(), unwrap_decoded(c.name)),
+						unwrap_decoded(c.value),
+					);
+				}
+
+				if let Some(module_storage) = module.storage {
+					let module_storage = unwrap_decoded(module_storage);
+					let module_prefix = unwrap_decoded(module_storage.prefix);
+					for entry in unwrap_decoded(module_storage.entries) {
+						let name = unwrap_decoded(entry.name);
+						let layout = match entry.ty {
+							StorageEntryType::Plain(value) => {
+								StorageLayout::Plain { value: unwrap_decoded(value) }
+							}
+							StorageEntryType::Map { hasher, key, value, .. } => {
+								StorageLayout::Map {
+									hasher,
+									key: unwrap_decoded(key),
+									value: unwrap_decoded(value),
+								}
+							}
+							StorageEntryType::DoubleMap {
+								hasher,
+								key1,
+								key2,
+								value,
+								key2_hasher,
+							} => StorageLayout::DoubleMap {
+								hasher1: hasher,
+								key1: unwrap_decoded(key1),
+								hasher2: key2_hasher,
+								key2: unwrap_decoded(key2),
+								value: unwrap_decoded(value),
+							},
+						};
+						let info = StorageInfo {
+							prefix: module_prefix_raw(module_prefix.as_bytes(), name.as_bytes()),
+							module_prefix: module_prefix.clone(),
+							name: name.clone(),
+							layout,
+							optional: entry.modifier == StorageEntryModifier::Optional,
+							default: unwrap_decoded(entry.default),
+						};
+						storage.insert((module_name.clone(), name), info);
+					}
+				}
+			}
+		} else {
+			panic!("Unsupported metadata version. Please make an issue.")
+		}
+
+		Self { spec_name, spec_version, raw, constants, storage }
+	}
+
+	/// The encoded value of a constant, if it exists.
+	pub fn constant(&self, module: &str, name: &str) -> Option<&[u8]> {
+		self.constants.get(&(module.to_string(), name.to_string())).map(|v| v.as_slice())
+	}
+
+	/// The info of a storage item, if it exists.
+	pub fn storage(&self, module: &str, name: &str) -> Option<&StorageInfo> {
+		self.storage.get(&(module.to_string(), name.to_string()))
+	}
+
+	/// Iterate over all storage items, as `((module, name), info)`.
+	pub fn storage_items(&self) -> impl Iterator<Item = (&(String, String), &StorageInfo)> {
+		self.storage.iter()
+	}
+
+	/// Find the storage item to which the given final key belongs, if any.
+	pub fn storage_of_key(&self, key: &[u8]) -> Option<&StorageInfo> {
+		if key.len() < 32 {
+			return None;
+		}
+		self.storage.values().find(|info| key.starts_with(&info.prefix))
+	}
+}
+
+/// Set the directory in which the raw metadata of each runtime version is persisted.
+///
+/// If `None` (the default), the metadata is only cached in memory.
+pub fn set_cache_dir(dir: Option<PathBuf>) {
+	*CACHE_DIR.lock().unwrap() = dir;
+}
+
+fn cache_file(dir: &Path, spec_name: &str, spec_version: u32) -> PathBuf {
+	dir.join(format!("{}-{}.metadata", spec_name, spec_version))
+}
+
+/// Get the decoded metadata of the runtime at the given block.
+///
+/// The metadata is only downloaded and decoded once per `(spec_name, spec_version)`. Each call
+/// still fetches the runtime version at `at`, which is cheap.
+pub async fn get(client: &Client, at: Hash) -> Arc<Metadata> {
+	let version = crate::get_runtime_version(client, at).await;
+	let key = (version.spec_name.to_string(), version.spec_version);
+
+	if let Some(metadata) = CACHE.lock().unwrap().get(&key) {
+		return metadata.clone();
+	}
+
+	let maybe_dir = CACHE_DIR.lock().unwrap().clone();
+	let from_disk =
+		maybe_dir.as_ref().and_then(|dir| fs::read(cache_file(dir, &key.0, key.1)).ok());
+	let raw = match from_disk {
+		Some(raw) => raw,
+		None => {
+			let raw = crate::get_metadata(client, at).await.0;
+			if let Some(dir) = maybe_dir {
+				let _ = fs::create_dir_all(&dir);
+				if let Err(why) = fs::write(cache_file(&dir, &key.0, key.1), &raw) {
+					log::warn!(
+						target: crate::LOG_TARGET,
+						"failed to persist metadata of {:?}: {:?}",
+						key,
+						why
+					);
+				}
+			}
+			raw
+		}
+	};
+
+	let metadata = Arc::new(Metadata::decode(key.0.clone(), key.1, raw));
+	CACHE.lock().unwrap().insert(key, metadata.clone());
+	metadata
+}
@@ -0,0 +1,279 @@
+//! Progress and throughput reporting for long running scrapes.
+//!
+//! A [`Progress`] is fed with the number of items (e.g. keys) and bytes that were processed, per
+//! module. Periodically, and once when finished, it produces a [`ProgressReport`], which is:
+//!
+//! - logged, at `info` level, under the configured log target.
+//! - drawn as a progress bar on stderr, if enabled and stderr is a terminal.
+//! - passed to the configured callback, if any, so that library users can render it themselves.
+//!
+//! The final report is also logged with a per module breakdown.
+
+use std::{
+	collections::BTreeMap,
+	fmt,
+	io::{IsTerminal, Write},
+	sync::Arc,
+	time::{Duration, Instant},
+};
+
+/// The width of the progress bar, in characters.
+const BAR_WIDTH: usize = 30;
+
+/// Minimum time between two redraws of the progress bar.
+const BAR_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A callback that receives every report.
+pub type ProgressCallback = Arc<dyn Fn(&ProgressReport) + Send + Sync>;
+
+/// Configuration of a [`Progress`].
+#[derive(Clone)]
+pub struct ProgressConfig {
+	/// The log target of the reports.
+	pub log_target: &'static str,
+	/// Minimum time between two periodic reports.
+	pub interval: Duration,
+	/// Draw a progress bar on stderr, if it is a terminal.
+	pub bar: bool,
+	/// Called with every report, including the final one.
+	pub callback: Option<ProgressCallback>,
+}
+
+impl ProgressConfig {
+	/// A configuration that only logs under `log_target`, every 5 seconds.
+	pub fn new(log_target: &'static str) -> Self {
+		Self { log_target, interval: Duration::from_secs(5), bar: false, callback: None }
+	}
+}
+
+impl fmt::Debug for ProgressConfig {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("ProgressConfig")
+			.field("log_target", &self.log_target)
+			.field("interval", &self.interval)
+			.field("bar", &self.bar)
+			.field("callback", &self.callback.is_some())
+			.finish()
+	}
+}
+
+/// The number of items and bytes processed so far.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct Throughput {
+	/// The number of items.
+	pub items: usize,
+	/// The number of bytes.
+	pub bytes: usize,
+}
+
+/// A snapshot of a [`Progress`].
+#[derive(Debug, Clone)]
+pub struct ProgressReport {
+	/// What is being processed, e.g. `downloading values`.
+	pub label: String,
+	/// The unit of the items, e.g. `keys`.
+	pub unit: &'static str,
+	/// Everything processed so far.
+	pub done: Throughput,
+	/// The total number of items, if known.
+	pub total: Option<usize>,
+	/// The time since the start.
+	pub elapsed: Duration,
+	/// True if this is the final report.
+	pub finished: bool,
+	/// What was processed so far, per module.
+	pub modules: BTreeMap<String, Throughput>,
+}
+
+impl ProgressReport {
+	fn per_second(&self, amount: usize) -> f64 {
+		amount as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+	}
+
+	/// The number of items processed per second.
+	pub fn items_per_second(&self) -> f64 {
+		self.per_second(self.done.items)
+	}
+
+	/// The number of bytes processed per second.
+	pub fn bytes_per_second(&self) -> f64 {
+		self.per_second(self.done.bytes)
+	}
+
+	/// The fraction of items processed, if the total is known.
+	pub fn fraction(&self) -> Option<f64> {
+		self.total.map(|total| (self.done.items as f64 / total.max(1) as f64).min(1.0))
+	}
+
+	/// The estimated time until all items are processed, if the total is known.
+	pub fn eta(&self) -> Option<Duration> {
+		let total = self.total?;
+		let done = self.done.items.max(1);
+		Some(self.elapsed.mul_f64(total.saturating_sub(done) as f64 / done as f64))
+	}
+}
+
+impl fmt::Display for ProgressReport {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}: {}", self.label, self.done.items)?;
+		if let Some(total) = self.total {
+			write!(f, " / {}", total)?;
+		}
+		write!(
+			f,
+			" {} ({}) in {:.1?}, {:.0} {}/s, {}/s",
+			self.unit,
+			Bytes(self.done.bytes as f64),
+			self.elapsed,
+			self.items_per_second(),
+			self.unit,
+			Bytes(self.bytes_per_second()),
+		)?;
+		match self.eta() {
+			Some(eta) if !self.finished => write!(f, ", ETA {:.0?}", eta),
+			_ => Ok(()),
+		}
+	}
+}
+
+/// A number of bytes, displayed with a binary unit.
+struct Bytes(f64);
+
+impl fmt::Display for Bytes {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+		let mut size = self.0;
+		let mut unit = 0;
+		while size >= 1024.0 && unit < UNITS.len() - 1 {
+			size /= 1024.0;
+			unit += 1;
+		}
+		write!(f, "{:.1} {}", size, UNITS[unit])
+	}
+}
+
+/// Tracks the progress of a long running operation, see the module docs.
+pub struct Progress {
+	config: ProgressConfig,
+	label: String,
+	unit: &'static str,
+	total: Option<usize>,
+	done: Throughput,
+	modules: BTreeMap<String, Throughput>,
+	start: Instant,
+	last_report: Instant,
+	last_draw: Instant,
+	bar: bool,
+}
+
+impl Progress {
+	/// Start tracking `total` items of `unit`, e.g. `keys`.
+	pub fn new(
+		config: ProgressConfig,
+		label: impl Into<String>,
+		unit: &'static str,
+		total: Option<usize>,
+	) -> Self {
+		let now = Instant::now();
+		let bar = config.bar && std::io::stderr().is_terminal();
+		Self {
+			config,
+			label: label.into(),
+			unit,
+			total,
+			done: Default::default(),
+			modules: Default::default(),
+			start: now,
+			last_report: now,
+			last_draw: now,
+			bar,
+		}
+	}
+
+	/// Record that `items` items, of `bytes` bytes in total, of `module` were processed.
+	///
+	/// This reports the progress if the configured interval has passed since the last report, and
+	/// redraws the progress bar at most every [`BAR_INTERVAL`].
+	pub fn record(&mut self, module: &str, items: usize, bytes: usize) {
+		if !self.modules.contains_key(module) {
+			self.modules.insert(module.to_string(), Default::default());
+		}
+		let per_module = self.modules.get_mut(module).expect("inserted above; qed");
+		for throughput in [&mut self.done, per_module] {
+			throughput.items += items;
+			throughput.bytes += bytes;
+		}
+		if self.bar && self.last_draw.elapsed() >= BAR_INTERVAL {
+			self.last_draw = Instant::now();
+			self.draw_bar();
+		}
+		if self.last_report.elapsed() >= self.config.interval {
+			self.last_report = Instant::now();
+			let report = self.report(false);
+			log::info!(target: self.config.log_target, "{}", report);
+			if let Some(callback) = &self.config.callback {
+				callback(&report);
+			}
+		}
+	}
+
+	/// The current report.
+	pub fn report(&self, finished: bool) -> ProgressReport {
+		ProgressReport { modules: self.modules.clone(), ..self.totals(finished) }
+	}
+
+	/// The current report, without the per module breakdown.
+	fn totals(&self, finished: bool) -> ProgressReport {
+		ProgressReport {
+			label: self.label.clone(),
+			unit: self.unit,
+			done: self.done,
+			total: self.total,
+			elapsed: self.start.elapsed(),
+			finished,
+			modules: Default::default(),
+		}
+	}
+
+	/// Finish, and return the final report.
+	///
+	/// The final report is logged with a per module breakdown, largest first.
+	pub fn finish(self) -> ProgressReport {
+		if self.bar {
+			self.draw_bar();
+			eprintln!();
+		}
+		let report = self.report(true);
+		log::info!(target: self.config.log_target, "{}", report);
+		let mut modules = report.modules.iter().collect::<Vec<_>>();
+		modules.sort_by_key(|(_, throughput)| std::cmp::Reverse(throughput.bytes));
+		for (module, throughput) in modules {
+			log::info!(
+				target: self.config.log_target,
+				"  {}: {} {} ({})",
+				module,
+				throughput.items,
+				self.unit,
+				Bytes(throughput.bytes as f64),
+			);
+		}
+		if let Some(callback) = &self.config.callback {
+			callback(&report);
+		}
+		report
+	}
+
+	fn draw_bar(&self) {
+		let report = self.totals(false);
+		let filled = report.fraction().map_or(0, |f| (f * BAR_WIDTH as f64) as usize);
+		let mut stderr = std::io::stderr();
+		let _ = write!(
+			stderr,
+			"\r[{}{}] {}\x1b[K",
+			"#".repeat(filled),
+			"-".repeat(BAR_WIDTH - filled),
+			report,
+		);
+		let _ = stderr.flush();
+	}
+}
@@ -0,0 +1,180 @@
+//! Sampling a set of storage keys over many blocks.
+//!
+//! This is useful to build a time series of some values, such as the total issuance or the number
+//! of validators, over a long period of time. Blocks are fetched in parallel, with a cap on the
+//! number of concurrent requests, and the raw results can be cached on disk so that re-running an
+//! analysis does not hit the node again.
+
+use crate::{Client, Hash, StorageKey, LOG_TARGET};
+use codec::{Decode, Encode};
+use futures::{stream, StreamExt};
+use std::{
+	collections::BTreeMap,
+	fmt, fs,
+	path::{Path, PathBuf},
+};
+
+/// The block number type used for sampling.
+pub type BlockNumber = u32;
+
+/// The blocks at which a sample should be taken.
+#[derive(Debug, Clone)]
+pub enum Blocks {
+	/// An explicit list of block numbers.
+	Numbers(Vec<BlockNumber>),
+	/// Every `step` blocks, starting from `from` up to `to`, inclusive.
+	Stride { from: BlockNumber, to: BlockNumber, step: BlockNumber },
+	/// The last block of every `step` eras, starting from era `from` up to era `to`, inclusive.
+	///
+	/// All these eras must be in the given `index`, see
+	/// [`crate::helpers::EraBoundaryIndex::update`].
+	#[cfg(feature = "helpers")]
+	Eras { index: crate::helpers::EraBoundaryIndex, from: u32, to: u32, step: u32 },
+}
+
+impl Blocks {
+	/// Convert `self` into the final list of block numbers.
+	pub fn numbers(&self) -> Result<Vec<BlockNumber>, SampleError> {
+		match self {
+			Self::Numbers(numbers) => Ok(numbers.clone()),
+			Self::Stride { step: 0, .. } => Err(SampleError::ZeroStep),
+			Self::Stride { from, to, step } => Ok((*from..=*to).step_by(*step as usize).collect()),
+			#[cfg(feature = "helpers")]
+			Self::Eras { step: 0, .. } => Err(SampleError::ZeroStep),
+			#[cfg(feature = "helpers")]
+			Self::Eras { index, from, to, step } => (*from..=*to)
+				.step_by(*step as usize)
+				.map(|era| index.last_block(era).ok_or(SampleError::EraNotIndexed(era)))
+				.collect(),
+		}
+	}
+}
+
+/// The errors of [`sample`].
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum SampleError {
+	/// The step of a stride is zero.
+	ZeroStep,
+	/// The given block number does not exist, e.g. it is past the head of the chain.
+	BlockNotFound(BlockNumber),
+	/// The given era is not in the index of [`Blocks::Eras`].
+	EraNotIndexed(u32),
+}
+
+impl fmt::Display for SampleError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::ZeroStep => write!(f, "stride step cannot be zero"),
+			Self::BlockNotFound(number) => write!(f, "block {} does not exist", number),
+			Self::EraNotIndexed(era) => write!(f, "era {} is not indexed", era),
+		}
+	}
+}
+
+impl std::error::Error for SampleError {}
+
+/// Configuration of [`sample`].
+#[derive(Debug, Clone)]
+pub struct SampleConfig {
+	/// Maximum number of blocks being fetched at the same time.
+	pub concurrency: usize,
+	/// The directory in which the raw values of each block are cached, if any.
+	pub cache_dir: Option<PathBuf>,
+}
+
+impl Default for SampleConfig {
+	fn default() -> Self {
+		Self { concurrency: 8, cache_dir: None }
+	}
+}
+
+/// The values of all the sampled keys at a single block.
+#[derive(Debug, Clone)]
+pub struct SamplePoint<T> {
+	/// The number of the block.
+	pub number: BlockNumber,
+	/// The hash of the block.
+	pub hash: Hash,
+	/// The value of each key, in the same order as the keys given to [`sample`]. `None` if the key
+	/// did not exist, or failed to decode.
+	pub values: Vec<Option<T>>,
+}
+
+type RawValues = BTreeMap<Vec<u8>, Option<Vec<u8>>>;
+
+fn cache_file(dir: &Path, hash: Hash) -> PathBuf {
+	dir.join(format!("{:?}.sample", hash))
+}
+
+fn read_cache(dir: &Path, hash: Hash) -> RawValues {
+	fs::read(cache_file(dir, hash))
+		.ok()
+		.and_then(|bytes| <Vec<(Vec<u8>, Option<Vec<u8>>)>>::decode(&mut &*bytes).ok())
+		.map(|pairs| pairs.into_iter().collect())
+		.unwrap_or_default()
+}
+
+fn write_cache(dir: &Path, hash: Hash, values: &RawValues) {
+	let pairs = values.iter().map(|(k, v)| (k.clone(), v.clone())).collect::<Vec<_>>();
+	let _ = fs::create_dir_all(dir);
+	if let Err(why) = fs::write(cache_file(dir, hash), pairs.encode()) {
+		log::warn!(target: LOG_TARGET, "failed to write sample cache of {:?}: {:?}", hash, why);
+	}
+}
+
+async fn sample_at<T: Decode>(
+	keys: &[StorageKey],
+	number: BlockNumber,
+	client: &Client,
+	config: &SampleConfig,
+) -> Result<SamplePoint<T>, SampleError> {
+	let hash =
+		crate::get_block_hash(client, number).await.ok_or(SampleError::BlockNotFound(number))?;
+
+	let mut raw = config.cache_dir.as_ref().map(|d| read_cache(d, hash)).unwrap_or_default();
+	let missing = keys.iter().filter(|k| !raw.contains_key(&k.0)).cloned().collect::<Vec<_>>();
+	if !missing.is_empty() {
+		log::debug!(target: LOG_TARGET, "fetching {} keys at #{}", missing.len(), number);
+		// keys that are not returned at all do not exist.
+		raw.extend(missing.iter().map(|k| (k.0.clone(), None)));
+		let fetched = crate::query_storage_at(missing, client, hash).await;
+		raw.extend(fetched.into_iter().map(|(k, v)| (k.0, v.map(|v| v.0))));
+		if let Some(dir) = config.cache_dir.as_ref() {
+			write_cache(dir, hash, &raw);
+		}
+	}
+
+	let values = keys
+		.iter()
+		.map(|k| {
+			raw.get(&k.0).cloned().flatten().and_then(|v| {
+				<T as Decode>::decode(&mut &*v)
+					.map_err(|e| {
+						log::warn!(target: LOG_TARGET, "failed to decode {:?} at #{}: {:?}", k, number, e);
+					})
+					.ok()
+			})
+		})
+		.collect::<Vec<_>>();
+
+	Ok(SamplePoint { number, hash, values })
+}
+
+/// Sample the values of `keys` at each of the given `blocks`.
+///
+/// All keys must have the same value type `T`. The returned points are sorted in the same order as
+/// the `blocks`. Fails if any of the blocks does not exist.
+pub async fn sample<T: Decode>(
+	keys: &[StorageKey],
+	blocks: Blocks,
+	client: &Client,
+	config: &SampleConfig,
+) -> Result<Vec<SamplePoint<T>>, SampleError> {
+	stream::iter(blocks.numbers()?)
+		.map(|number| sample_at::<T>(keys, number, client, config))
+		.buffered(config.concurrency.max(1))
+		.collect::<Vec<_>>()
+		.await
+		.into_iter()
+		.collect()
+}